@@ -0,0 +1,72 @@
+/*
+ * Frame pointer based stack walker.
+ *
+ * The target spec keeps frame pointers (`eliminate-frame-pointer: false`), so
+ * every frame starts with the saved rbp of the caller followed by the return
+ * address:
+ *     [rbp + 8]  return address
+ *     [rbp]      caller's rbp
+ */
+
+/// Maximum number of frames followed by a walk
+pub const MAX_FRAMES: usize = 16;
+
+/// Fill `frames` with the return addresses of the callers of this function,
+/// skipping the innermost `skip` frames. Returns the number of frames stored.
+#[inline(never)]
+pub fn capture(skip: usize, frames: &mut [u64]) -> usize {
+    let rbp: u64;
+    unsafe {
+        asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags));
+    }
+    unsafe { capture_from(rbp, skip, frames) }
+}
+
+/// Walk the frame chain starting at the frame pointer `rbp`.
+///
+/// This function is unsafe because `rbp` must point to a valid frame.
+pub unsafe fn capture_from(mut rbp: u64, mut skip: usize, frames: &mut [u64]) -> usize {
+    let mut depth = 0;
+    let mut walked = 0;
+    while depth < frames.len() && walked < MAX_FRAMES + skip && is_plausible_frame(rbp) {
+        let return_addr = *((rbp + 8) as *const u64);
+        let next = *(rbp as *const u64);
+        if return_addr == 0 {
+            break;
+        }
+        if skip > 0 {
+            skip -= 1;
+        } else {
+            frames[depth] = return_addr;
+            depth += 1;
+        }
+        // stacks grow down, so the caller's frame must be above ours
+        if next <= rbp {
+            break;
+        }
+        rbp = next;
+        walked += 1;
+    }
+    depth
+}
+
+fn is_plausible_frame(rbp: u64) -> bool {
+    // non-null, aligned and outside the null page
+    rbp != 0 && rbp % 8 == 0 && rbp >= 0x1000
+}
+
+/// Print the return addresses in `frames`
+pub fn print(frames: &[u64]) {
+    for (i, addr) in frames.iter().enumerate() {
+        serial_println!("  #{:<2} {:#018x}", i, addr);
+    }
+}
+
+/// Print the call stack of the current function
+#[allow(dead_code)]
+pub fn print_current() {
+    let mut frames = [0u64; MAX_FRAMES];
+    let depth = capture(1, &mut frames);
+    serial_println!("backtrace:");
+    print(&frames[..depth]);
+}
@@ -0,0 +1,121 @@
+/*
+ * Minimal ACPI table parser.
+ *
+ * The RSDP is searched in the first KiB of the EBDA and in the BIOS area
+ * 0xE0000-0xFFFFF. It points to the RSDT (ACPI 1.0) or XSDT (ACPI 2.0+), which
+ * list the physical addresses of all other tables. All tables are read through
+ * the physical memory offset mapping set up by the bootloader.
+ */
+use super::apic::{ApicInfo, InterruptOverride, IoApicInfo};
+use core::ptr;
+use x86_64::{PhysAddr, VirtAddr};
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const SDT_HEADER_SIZE: u64 = 36;
+
+/// Read a value of type T at the physical address `paddr`
+unsafe fn read_phys<T: Copy>(paddr: u64, physical_memory_offset: VirtAddr) -> T {
+    ptr::read_unaligned((physical_memory_offset + paddr).as_ptr::<T>())
+}
+
+fn checksum_ok(paddr: u64, len: u64, physical_memory_offset: VirtAddr) -> bool {
+    let sum = (0..len).fold(0u8, |sum, i| {
+        sum.wrapping_add(unsafe { read_phys::<u8>(paddr + i, physical_memory_offset) })
+    });
+    sum == 0
+}
+
+/// Search [start, end) on 16 byte boundaries for a valid RSDP
+fn scan_rsdp(start: u64, end: u64, physical_memory_offset: VirtAddr) -> Option<u64> {
+    (start..end).step_by(16).find(|&addr| {
+        let signature: [u8; 8] = unsafe { read_phys(addr, physical_memory_offset) };
+        &signature == RSDP_SIGNATURE && checksum_ok(addr, 20, physical_memory_offset)
+    })
+}
+
+fn find_rsdp(physical_memory_offset: VirtAddr) -> Option<u64> {
+    // the real mode segment of the EBDA is stored at 0x40e
+    let ebda = (unsafe { read_phys::<u16>(0x40e, physical_memory_offset) } as u64) << 4;
+    if ebda != 0 {
+        if let Some(rsdp) = scan_rsdp(ebda, ebda + 1024, physical_memory_offset) {
+            return Some(rsdp);
+        }
+    }
+    scan_rsdp(0xe0000, 0x100000, physical_memory_offset)
+}
+
+/// Return the physical address of the first ACPI table with the given signature
+pub fn find_table(signature: &[u8; 4], physical_memory_offset: VirtAddr) -> Option<PhysAddr> {
+    let rsdp = find_rsdp(physical_memory_offset)?;
+    let revision: u8 = unsafe { read_phys(rsdp + 15, physical_memory_offset) };
+    // XSDT entries are 64-bit, RSDT entries 32-bit
+    let (root, entry_size) = if revision >= 2 {
+        (unsafe { read_phys::<u64>(rsdp + 24, physical_memory_offset) }, 8)
+    } else {
+        (unsafe { read_phys::<u32>(rsdp + 16, physical_memory_offset) } as u64, 4)
+    };
+
+    let length: u32 = unsafe { read_phys(root + 4, physical_memory_offset) };
+    let entries = (length as u64 - SDT_HEADER_SIZE) / entry_size;
+    for i in 0..entries {
+        let entry = root + SDT_HEADER_SIZE + i * entry_size;
+        let table = if entry_size == 8 {
+            unsafe { read_phys::<u64>(entry, physical_memory_offset) }
+        } else {
+            unsafe { read_phys::<u32>(entry, physical_memory_offset) as u64 }
+        };
+        let table_signature: [u8; 4] = unsafe { read_phys(table, physical_memory_offset) };
+        if &table_signature == signature {
+            let table_length: u32 = unsafe { read_phys(table + 4, physical_memory_offset) };
+            if checksum_ok(table, table_length as u64, physical_memory_offset) {
+                return Some(PhysAddr::new(table));
+            }
+        }
+    }
+    None
+}
+
+/// Parse the MADT ("APIC" table) for the local APIC, I/O APICs, processors
+/// and ISA interrupt overrides
+pub fn parse_madt(physical_memory_offset: VirtAddr) -> Option<ApicInfo> {
+    let madt = find_table(b"APIC", physical_memory_offset)?.as_u64();
+    let read_u8 = |addr: u64| unsafe { read_phys::<u8>(addr, physical_memory_offset) };
+    let read_u16 = |addr: u64| unsafe { read_phys::<u16>(addr, physical_memory_offset) };
+    let read_u32 = |addr: u64| unsafe { read_phys::<u32>(addr, physical_memory_offset) };
+    let read_u64 = |addr: u64| unsafe { read_phys::<u64>(addr, physical_memory_offset) };
+
+    let mut info = ApicInfo::new(read_u32(madt + 36) as u64);
+    let end = madt + read_u32(madt + 4) as u64;
+    let mut entry = madt + 44;
+    while entry + 2 <= end {
+        let entry_type = read_u8(entry);
+        let entry_len = read_u8(entry + 1) as u64;
+        if entry_len < 2 {
+            break;
+        }
+        match entry_type {
+            // processor local APIC, bit 0 of the flags: enabled
+            0 => {
+                if read_u32(entry + 4) & 1 == 1 {
+                    info.add_processor(read_u8(entry + 3));
+                }
+            }
+            1 => info.add_io_apic(IoApicInfo {
+                id: read_u8(entry + 2),
+                address: read_u32(entry + 4) as u64,
+                gsi_base: Some(read_u32(entry + 8)),
+            }),
+            2 => info.add_override(InterruptOverride {
+                source: read_u8(entry + 3),
+                gsi: read_u32(entry + 4),
+                flags: read_u16(entry + 8),
+                io_apic: None,
+            }),
+            // 64-bit local APIC address override
+            5 => info.local_apic_address = read_u64(entry + 4),
+            _ => {}
+        }
+        entry += entry_len;
+    }
+    Some(info)
+}
@@ -0,0 +1,376 @@
+/*
+ * Local APIC and I/O APIC.
+ *
+ * Each CPU has a local APIC which receives interrupts and takes the EOI. The
+ * I/O APIC replaces the 8259 PICs for external interrupts: every input pin
+ * (global system interrupt, GSI) has a redirection entry telling which vector
+ * to raise on which local APIC. ISA IRQs are identity mapped to GSIs unless
+ * the firmware reports an interrupt source override.
+ *
+ * Both are memory mapped, see `init` for how the registers are mapped.
+ */
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+pub const MAX_CPUS: usize = 16;
+const MAX_IO_APICS: usize = 4;
+const MAX_OVERRIDES: usize = 16;
+
+const IA32_APIC_BASE_MSR: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+
+/* local APIC register offsets */
+const LAPIC_ID: usize = 0x20;
+const LAPIC_TPR: usize = 0x80;
+const LAPIC_EOI: usize = 0xb0;
+const LAPIC_ISR: usize = 0x100;
+const LAPIC_SVR: usize = 0xf0;
+const LAPIC_ICR_LOW: usize = 0x300;
+const LAPIC_ICR_HIGH: usize = 0x310;
+const LAPIC_LVT_TIMER: usize = 0x320;
+const LAPIC_LVT_LINT0: usize = 0x350;
+const LAPIC_LVT_LINT1: usize = 0x360;
+const LVT_MASKED: u32 = 1 << 16;
+const SVR_ENABLE: u32 = 1 << 8;
+
+/* interrupt command register */
+const ICR_DELIVERY_NMI: u32 = 0b100 << 8;
+const ICR_DELIVERY_INIT: u32 = 0b101 << 8;
+const ICR_DELIVERY_STARTUP: u32 = 0b110 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/* I/O APIC registers, accessed indirectly through IOREGSEL and IOWIN */
+const IOAPIC_VERSION: u32 = 0x01;
+const IOAPIC_REDIRECTION_TABLE: u32 = 0x10;
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+#[derive(Debug, Clone, Copy)]
+pub struct IoApicInfo {
+    pub id: u8,
+    pub address: u64,
+    /// None if the firmware does not tell (MP tables), `init` then numbers the
+    /// I/O APICs one after another
+    pub gsi_base: Option<u32>,
+}
+
+/// An ISA IRQ connected to a different GSI or with non-ISA polarity/trigger mode
+#[derive(Debug, Clone, Copy)]
+pub struct InterruptOverride {
+    pub source: u8,
+    pub gsi: u32,
+    pub flags: u16,
+    /// If set, `gsi` is a pin of the I/O APIC with this id (MP tables)
+    pub io_apic: Option<u8>,
+}
+
+/// Interrupt controller topology as reported by ACPI or the MP tables
+#[derive(Debug)]
+pub struct ApicInfo {
+    pub local_apic_address: u64,
+    pub processors: [Option<u8>; MAX_CPUS],
+    pub io_apics: [Option<IoApicInfo>; MAX_IO_APICS],
+    pub overrides: [Option<InterruptOverride>; MAX_OVERRIDES],
+}
+
+impl ApicInfo {
+    pub fn new(local_apic_address: u64) -> Self {
+        ApicInfo {
+            local_apic_address,
+            processors: [None; MAX_CPUS],
+            io_apics: [None; MAX_IO_APICS],
+            overrides: [None; MAX_OVERRIDES],
+        }
+    }
+
+    pub fn add_processor(&mut self, apic_id: u8) {
+        if let Some(slot) = self.processors.iter_mut().find(|p| p.is_none()) {
+            *slot = Some(apic_id);
+        }
+    }
+
+    pub fn add_io_apic(&mut self, io_apic: IoApicInfo) {
+        if let Some(slot) = self.io_apics.iter_mut().find(|p| p.is_none()) {
+            *slot = Some(io_apic);
+        }
+    }
+
+    pub fn add_override(&mut self, over: InterruptOverride) {
+        if let Some(slot) = self.overrides.iter_mut().find(|p| p.is_none()) {
+            *slot = Some(over);
+        }
+    }
+
+    /// Find the GSI and the redirection flags of an ISA IRQ
+    fn isa_irq_to_gsi(&self, irq: u8) -> (u32, u64) {
+        match self.overrides.iter().filter_map(|o| *o).find(|o| o.source == irq) {
+            Some(over) => {
+                let mut flags = 0;
+                // polarity: 0b11 active low, trigger mode: 0b11 level
+                if over.flags & 0b11 == 0b11 {
+                    flags |= REDIRECTION_ACTIVE_LOW;
+                }
+                if (over.flags >> 2) & 0b11 == 0b11 {
+                    flags |= REDIRECTION_LEVEL;
+                }
+                let gsi_base = over.io_apic.map_or(0, |id| {
+                    self.io_apics
+                        .iter()
+                        .filter_map(|io_apic| *io_apic)
+                        .find(|io_apic| io_apic.id == id)
+                        .and_then(|io_apic| io_apic.gsi_base)
+                        .unwrap_or(0)
+                });
+                (gsi_base + over.gsi, flags)
+            }
+            // ISA interrupts are edge triggered and active high
+            None => (irq as u32, 0),
+        }
+    }
+}
+
+/// Virtual addresses of the mapped registers, 0 until `init` ran
+static LOCAL_APIC: AtomicU64 = AtomicU64::new(0);
+const NOT_MAPPED: AtomicU64 = AtomicU64::new(0);
+static IO_APICS: [AtomicU64; MAX_IO_APICS] = [NOT_MAPPED; MAX_IO_APICS];
+
+pub struct LocalApic {
+    base: u64,
+}
+
+impl LocalApic {
+    /// The local APIC of the current CPU, if it has been mapped
+    pub fn get() -> Option<LocalApic> {
+        match LOCAL_APIC.load(Ordering::Relaxed) {
+            0 => None,
+            base => Some(LocalApic { base }),
+        }
+    }
+
+    pub fn read(&self, reg: usize) -> u32 {
+        unsafe { ptr::read_volatile((self.base as usize + reg) as *const u32) }
+    }
+
+    pub fn write(&self, reg: usize, value: u32) {
+        unsafe { ptr::write_volatile((self.base as usize + reg) as *mut u32, value) }
+    }
+
+    pub fn id(&self) -> u8 {
+        (self.read(LAPIC_ID) >> 24) as u8
+    }
+
+    /// Enable the local APIC of the current CPU
+    pub fn enable(&self) {
+        unsafe {
+            let mut base_msr = Msr::new(IA32_APIC_BASE_MSR);
+            let value = base_msr.read();
+            base_msr.write(value | APIC_BASE_ENABLE);
+        }
+        // accept all priorities, mask the local interrupt pins and the APIC timer
+        self.write(LAPIC_TPR, 0);
+        self.write(LAPIC_LVT_TIMER, LVT_MASKED);
+        self.write(LAPIC_LVT_LINT0, LVT_MASKED);
+        self.write(LAPIC_LVT_LINT1, LVT_MASKED);
+        self.write(LAPIC_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+    }
+
+    /// Whether `vector` is being serviced, i.e. waits for an EOI
+    pub fn is_in_service(&self, vector: u8) -> bool {
+        // eight 32-bit registers, 16 bytes apart
+        let reg = LAPIC_ISR + 0x10 * (vector as usize / 32);
+        self.read(reg) & (1 << (vector % 32)) != 0
+    }
+
+    pub fn end_of_interrupt(&self) {
+        self.write(LAPIC_EOI, 0);
+    }
+
+    /// Send an inter-processor interrupt to the local APIC `apic_id` and wait
+    /// until it was accepted
+    pub fn send_ipi(&self, apic_id: u8, command: u32) {
+        // an interrupt handler sending its own IPI in between would change
+        // the destination of ours
+        interrupts::without_interrupts(|| {
+            self.write(LAPIC_ICR_HIGH, (apic_id as u32) << 24);
+            self.write(LAPIC_ICR_LOW, command);
+            while self.read(LAPIC_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+                core::sync::atomic::spin_loop_hint();
+            }
+        });
+    }
+
+    /// Send a non-maskable interrupt, the vector is ignored
+    pub fn send_nmi(&self, apic_id: u8) {
+        self.send_ipi(apic_id, ICR_DELIVERY_NMI);
+    }
+
+    /// Reset a processor into the wait-for-SIPI state
+    pub fn send_init(&self, apic_id: u8) {
+        self.send_ipi(apic_id, ICR_DELIVERY_INIT | ICR_LEVEL_ASSERT);
+    }
+
+    /// Start a processor waiting for SIPI in real mode at `page` * 4 KiB
+    pub fn send_startup(&self, apic_id: u8, page: u8) {
+        self.send_ipi(apic_id, ICR_DELIVERY_STARTUP | page as u32);
+    }
+}
+
+pub struct IoApic {
+    base: u64,
+    gsi_base: u32,
+}
+
+impl IoApic {
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            ptr::write_volatile(self.base as *mut u32, reg);
+            ptr::read_volatile((self.base + 0x10) as *const u32)
+        }
+    }
+
+    fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            ptr::write_volatile(self.base as *mut u32, reg);
+            ptr::write_volatile((self.base + 0x10) as *mut u32, value);
+        }
+    }
+
+    fn max_redirection_entries(&self) -> u32 {
+        ((self.read(IOAPIC_VERSION) >> 16) & 0xff) + 1
+    }
+
+    fn read_redirection(&self, pin: u32) -> u64 {
+        let low = self.read(IOAPIC_REDIRECTION_TABLE + 2 * pin) as u64;
+        let high = self.read(IOAPIC_REDIRECTION_TABLE + 2 * pin + 1) as u64;
+        (high << 32) | low
+    }
+
+    fn write_redirection(&self, pin: u32, entry: u64) {
+        self.write(IOAPIC_REDIRECTION_TABLE + 2 * pin, entry as u32);
+        self.write(IOAPIC_REDIRECTION_TABLE + 2 * pin + 1, (entry >> 32) as u32);
+    }
+
+    /// Mask all inputs
+    fn mask_all(&self) {
+        for pin in 0..self.max_redirection_entries() {
+            self.write_redirection(pin, REDIRECTION_MASKED);
+        }
+    }
+}
+
+/// Find the I/O APIC handling `gsi` and the pin number on it
+fn io_apic_for_gsi(gsi: u32) -> Option<(IoApic, u32)> {
+    let info = APIC_INFO.lock();
+    let info = info.as_ref()?;
+    for (index, io_apic) in info.io_apics.iter().enumerate() {
+        if let Some(IoApicInfo { gsi_base: Some(gsi_base), .. }) = io_apic {
+            let apic = IoApic {
+                base: IO_APICS[index].load(Ordering::Relaxed),
+                gsi_base: *gsi_base,
+            };
+            if gsi >= apic.gsi_base && gsi < apic.gsi_base + apic.max_redirection_entries() {
+                let pin = gsi - apic.gsi_base;
+                return Some((apic, pin));
+            }
+        }
+    }
+    None
+}
+
+static APIC_INFO: spin::Mutex<Option<ApicInfo>> = spin::Mutex::new(None);
+
+/// Route the ISA `irq` to `vector` on the local APIC with id `apic_id`
+pub fn route_isa_irq(irq: u8, vector: u8, apic_id: u8, masked: bool) {
+    let (gsi, flags) = match APIC_INFO.lock().as_ref() {
+        Some(info) => info.isa_irq_to_gsi(irq),
+        None => return,
+    };
+    if let Some((io_apic, pin)) = io_apic_for_gsi(gsi) {
+        let mut entry = vector as u64 | flags | ((apic_id as u64) << 56);
+        if masked {
+            entry |= REDIRECTION_MASKED;
+        }
+        io_apic.write_redirection(pin, entry);
+    }
+}
+
+/// Mask or unmask the redirection entry of the ISA `irq`
+pub fn set_isa_irq_masked(irq: u8, masked: bool) {
+    let gsi = match APIC_INFO.lock().as_ref() {
+        Some(info) => info.isa_irq_to_gsi(irq).0,
+        None => return,
+    };
+    if let Some((io_apic, pin)) = io_apic_for_gsi(gsi) {
+        let entry = io_apic.read_redirection(pin);
+        let entry = if masked {
+            entry | REDIRECTION_MASKED
+        } else {
+            entry & !REDIRECTION_MASKED
+        };
+        io_apic.write_redirection(pin, entry);
+    }
+}
+
+/// The processors reported by the firmware, by local APIC id
+pub fn processors() -> [Option<u8>; MAX_CPUS] {
+    match APIC_INFO.lock().as_ref() {
+        Some(info) => info.processors,
+        None => [None; MAX_CPUS],
+    }
+}
+
+/// Look for the APICs in the ACPI MADT or the MP tables, map their registers
+/// and enable the local APIC of the bootstrap processor. All I/O APIC inputs
+/// are left masked. Returns false if no APIC was found.
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    physical_memory_offset: VirtAddr,
+) -> bool {
+    use crate::mm::page_table::map_mmio;
+
+    let mut info = match super::acpi::parse_madt(physical_memory_offset)
+        .or_else(|| super::mptable::parse(physical_memory_offset))
+    {
+        Some(info) => info,
+        None => return false,
+    };
+    if info.io_apics.iter().all(|io_apic| io_apic.is_none()) {
+        return false;
+    }
+
+    let lapic = map_mmio(mapper, frame_allocator, PhysAddr::new(info.local_apic_address))
+        .expect("failed to map the local APIC");
+    LOCAL_APIC.store(lapic.as_u64(), Ordering::Relaxed);
+    // without a GSI base from the firmware, each I/O APIC starts where the previous one ended
+    let mut next_gsi = 0;
+    for (index, io_apic) in info.io_apics.iter_mut().enumerate() {
+        if let Some(io_apic) = io_apic {
+            let base = map_mmio(mapper, frame_allocator, PhysAddr::new(io_apic.address))
+                .expect("failed to map an I/O APIC");
+            IO_APICS[index].store(base.as_u64(), Ordering::Relaxed);
+            let gsi_base = *io_apic.gsi_base.get_or_insert(next_gsi);
+            let apic = IoApic { base: base.as_u64(), gsi_base };
+            next_gsi = gsi_base + apic.max_redirection_entries();
+            apic.mask_all();
+        }
+    }
+    serial_println!(
+        "apic: local APIC at {:#x}, {} CPU(s), {} I/O APIC(s)",
+        info.local_apic_address,
+        info.processors.iter().filter(|p| p.is_some()).count(),
+        info.io_apics.iter().filter(|p| p.is_some()).count()
+    );
+    *APIC_INFO.lock() = Some(info);
+
+    LocalApic::get().unwrap().enable();
+    true
+}
@@ -0,0 +1,89 @@
+/*
+ * HPET (High Precision Event Timer)
+ *
+ * Only the free running main counter is used, as a reference clock. ACPI
+ * describes the HPET in the "HPET" table, whose base address field (a Generic
+ * Address Structure) starts at offset 40.
+ *
+ * A main counter that is only 32 bits wide wraps every few minutes, and the
+ * clocksource would jump backwards. Such an HPET is ignored, the TSC or the
+ * PIT is used instead.
+ */
+use super::acpi;
+use core::ptr;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::paging::{FrameAllocator, Mapper, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+const CAPABILITIES: u64 = 0x00;
+const CONFIGURATION: u64 = 0x10;
+const MAIN_COUNTER: u64 = 0xf0;
+const CONFIG_ENABLE: u64 = 1;
+// COUNT_SIZE_CAP: the main counter is 64 bits wide
+const CAP_COUNTER_64BIT: u64 = 1 << 13;
+// the specification limits the counter period to 100 ns
+const MAX_PERIOD_FS: u64 = 0x05f5_e100;
+
+static BASE: AtomicU64 = AtomicU64::new(0);
+static PERIOD_FS: AtomicU64 = AtomicU64::new(0);
+
+fn read(reg: u64) -> u64 {
+    unsafe { ptr::read_volatile((BASE.load(Ordering::Relaxed) + reg) as *const u64) }
+}
+
+fn write(reg: u64, value: u64) {
+    unsafe { ptr::write_volatile((BASE.load(Ordering::Relaxed) + reg) as *mut u64, value) }
+}
+
+/// Find the HPET in the ACPI tables, map it and start the main counter.
+/// Returns false if there is no HPET.
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    physical_memory_offset: VirtAddr,
+) -> bool {
+    use crate::mm::page_table::map_mmio;
+
+    let table = match acpi::find_table(b"HPET", physical_memory_offset) {
+        Some(table) => table,
+        None => return false,
+    };
+    let address: u64 = unsafe {
+        ptr::read_unaligned((physical_memory_offset + table.as_u64() + 44).as_ptr::<u64>())
+    };
+    let base = map_mmio(mapper, frame_allocator, PhysAddr::new(address))
+        .expect("failed to map the HPET");
+    BASE.store(base.as_u64(), Ordering::Relaxed);
+
+    // the upper 32 bits hold the counter period in femtoseconds
+    let capabilities = read(CAPABILITIES);
+    let period = capabilities >> 32;
+    if period == 0 || period > MAX_PERIOD_FS {
+        serial_println!("hpet: invalid counter period {} fs, ignoring the HPET", period);
+        BASE.store(0, Ordering::Relaxed);
+        return false;
+    }
+    if capabilities & CAP_COUNTER_64BIT == 0 {
+        serial_println!("hpet: 32-bit main counter, ignoring the HPET");
+        BASE.store(0, Ordering::Relaxed);
+        return false;
+    }
+    PERIOD_FS.store(period, Ordering::Relaxed);
+    write(CONFIGURATION, read(CONFIGURATION) | CONFIG_ENABLE);
+    serial_println!("hpet: {} MHz main counter", 1_000_000_000 / period);
+    true
+}
+
+pub fn is_present() -> bool {
+    PERIOD_FS.load(Ordering::Relaxed) != 0
+}
+
+/// Raw value of the main counter
+pub fn counter() -> u64 {
+    read(MAIN_COUNTER)
+}
+
+/// Nanoseconds since the main counter was started
+pub fn nanos() -> u64 {
+    (counter() as u128 * PERIOD_FS.load(Ordering::Relaxed) as u128 / 1_000_000) as u64
+}
@@ -1,11 +1,133 @@
 use x86_64::structures::idt::InterruptStackFrame;
-use super::pic8259::{PICS, InterruptIndex};
+use crate::interrupts::irq::{self, IrqReturn};
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use core::task::{Context, Poll};
+use futures_util::stream::{Stream, StreamExt};
+use futures_util::task::AtomicWaker;
 use lazy_static::lazy_static;
 
-pub extern "x86-interrupt" fn keyboard_interrupt_handler(stack_frame: &mut InterruptStackFrame) {
+const KEYBOARD_IRQ: u8 = 1;
+const SCANCODE_QUEUE_SIZE: usize = 128;
+
+/*
+ * Lock-free single producer, single consumer queue of scancodes. The interrupt
+ * handler is the only producer and the ScancodeStream the only consumer, so head
+ * and tail are each written by one side only. One slot stays empty to tell a
+ * full queue from an empty one.
+ */
+pub struct ScancodeQueue {
+    buffer: [AtomicU8; SCANCODE_QUEUE_SIZE],
+    head: AtomicUsize,
+    tail: AtomicUsize,
+}
+
+const EMPTY_SLOT: AtomicU8 = AtomicU8::new(0);
+
+impl ScancodeQueue {
+    pub const fn new() -> Self {
+        ScancodeQueue {
+            buffer: [EMPTY_SLOT; SCANCODE_QUEUE_SIZE],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Returns false if the queue is full and the scancode was dropped
+    pub fn push(&self, scancode: u8) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let next = (tail + 1) % SCANCODE_QUEUE_SIZE;
+        if next == self.head.load(Ordering::Acquire) {
+            return false;
+        }
+        self.buffer[tail].store(scancode, Ordering::Relaxed);
+        self.tail.store(next, Ordering::Release);
+        true
+    }
+
+    pub fn pop(&self) -> Option<u8> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let scancode = self.buffer[head].load(Ordering::Relaxed);
+        self.head.store((head + 1) % SCANCODE_QUEUE_SIZE, Ordering::Release);
+        Some(scancode)
+    }
+}
+
+static SCANCODES: ScancodeQueue = ScancodeQueue::new();
+// task waiting in ScancodeStream::poll_next
+static WAKER: AtomicWaker = AtomicWaker::new();
+
+pub fn init() {
+    irq::register(KEYBOARD_IRQ, keyboard_interrupt_handler).expect("failed to register the keyboard IRQ");
+}
+
+/// Only read the scancode from the controller, it is decoded by the task
+/// reading the ScancodeStream
+pub fn keyboard_interrupt_handler(_stack_frame: &mut InterruptStackFrame) -> IrqReturn {
+    use x86_64::instructions::port::Port;
+
+    let mut port = Port::new(0x60);
+    let scancode: u8 = unsafe{port.read()};
+    if !SCANCODES.push(scancode) {
+        serial_println!("WARNING: scancode queue full; dropping keyboard input");
+    } else {
+        WAKER.wake();
+    }
+
+    IrqReturn::Handled
+}
+
+/// Async stream of the scancodes received by the interrupt handler. There may
+/// be only one, since the queue has a single consumer.
+pub struct ScancodeStream {
+    _private: (),
+}
+
+impl ScancodeStream {
+    pub fn new() -> Self {
+        static TAKEN: AtomicBool = AtomicBool::new(false);
+        if TAKEN.swap(true, Ordering::AcqRel) {
+            panic!("ScancodeStream::new should only be called once");
+        }
+        ScancodeStream { _private: () }
+    }
+}
+
+impl Stream for ScancodeStream {
+    type Item = u8;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<u8>> {
+        // fast path, avoids registering the waker
+        if let Some(scancode) = SCANCODES.pop() {
+            return Poll::Ready(Some(scancode));
+        }
+        WAKER.register(cx.waker());
+        // a scancode may have arrived before the waker was registered
+        match SCANCODES.pop() {
+            Some(scancode) => {
+                WAKER.take();
+                Poll::Ready(Some(scancode))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+/// Task echoing all key presses to the screen
+pub async fn print_keypresses() {
+    let mut scancodes = ScancodeStream::new();
+    while let Some(scancode) = scancodes.next().await {
+        handle_scancode(scancode);
+    }
+}
+
+/// Decode a scancode and echo the key to the screen
+pub fn handle_scancode(scancode: u8) {
     use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
     use spin::Mutex;
-    use x86_64::instructions::port::Port;
 
     lazy_static! {
         static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
@@ -15,12 +137,12 @@ pub extern "x86-interrupt" fn keyboard_interrupt_handler(stack_frame: &mut Inter
     }
 
     let mut keyboard = KEYBOARD.lock();
-    let mut port = Port::new(0x60);
-    let scancode: u8 = unsafe{port.read()};
 
     if scancode == 0x0e {
         use crate::drivers::vga_buffer::VGA_WRITER;
-        VGA_WRITER.lock().backspace();
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            VGA_WRITER.lock().backspace();
+        });
     } else {
         if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
             if let Some(key) = keyboard.process_keyevent(key_event) {
@@ -31,8 +153,4 @@ pub extern "x86-interrupt" fn keyboard_interrupt_handler(stack_frame: &mut Inter
             }
         }
     }
-
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
-}
\ No newline at end of file
+}
@@ -1,4 +1,10 @@
 pub mod vga_buffer;
 pub mod serial;
 pub mod pic8259;
-pub mod keyboard;
\ No newline at end of file
+pub mod keyboard;
+pub mod pit;
+pub mod rtc;
+pub mod acpi;
+pub mod mptable;
+pub mod apic;
+pub mod hpet;
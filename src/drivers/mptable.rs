@@ -0,0 +1,104 @@
+/*
+ * Intel MultiProcessor Specification tables.
+ *
+ * Older (virtual) machines without ACPI describe the processors and I/O APICs
+ * in the MP configuration table. Its floating pointer structure "_MP_" lives in
+ * the first KiB of the EBDA, the last KiB of base memory or the BIOS ROM.
+ */
+use super::apic::{ApicInfo, InterruptOverride, IoApicInfo};
+use core::ptr;
+use x86_64::VirtAddr;
+
+unsafe fn read_phys<T: Copy>(paddr: u64, physical_memory_offset: VirtAddr) -> T {
+    ptr::read_unaligned((physical_memory_offset + paddr).as_ptr::<T>())
+}
+
+fn scan_floating_pointer(start: u64, end: u64, physical_memory_offset: VirtAddr) -> Option<u64> {
+    (start..end).step_by(16).find(|&addr| {
+        let signature: [u8; 4] = unsafe { read_phys(addr, physical_memory_offset) };
+        let sum = (0..16).fold(0u8, |sum, i| {
+            sum.wrapping_add(unsafe { read_phys::<u8>(addr + i, physical_memory_offset) })
+        });
+        &signature == b"_MP_" && sum == 0
+    })
+}
+
+fn find_floating_pointer(physical_memory_offset: VirtAddr) -> Option<u64> {
+    let ebda = (unsafe { read_phys::<u16>(0x40e, physical_memory_offset) } as u64) << 4;
+    let base_mem_kb = unsafe { read_phys::<u16>(0x413, physical_memory_offset) } as u64;
+    // some firmware leaves the base memory size at 0
+    let base_mem_end = match base_mem_kb {
+        0 => (0, 0),
+        kb => (kb * 1024 - 1024, kb * 1024),
+    };
+    let candidates = [(ebda, ebda + 1024), base_mem_end, (0xf0000, 0x100000)];
+    candidates
+        .iter()
+        .filter(|(start, _)| *start != 0)
+        .find_map(|&(start, end)| scan_floating_pointer(start, end, physical_memory_offset))
+}
+
+/// Parse the MP configuration table
+pub fn parse(physical_memory_offset: VirtAddr) -> Option<ApicInfo> {
+    let floating = find_floating_pointer(physical_memory_offset)?;
+    let read_u8 = |addr: u64| unsafe { read_phys::<u8>(addr, physical_memory_offset) };
+    let read_u16 = |addr: u64| unsafe { read_phys::<u16>(addr, physical_memory_offset) };
+    let read_u32 = |addr: u64| unsafe { read_phys::<u32>(addr, physical_memory_offset) };
+
+    let config = read_u32(floating + 4) as u64;
+    // a default configuration (no table) is not supported
+    if config == 0 || &unsafe { read_phys::<[u8; 4]>(config, physical_memory_offset) } != b"PCMP" {
+        return None;
+    }
+
+    let mut info = ApicInfo::new(read_u32(config + 36) as u64);
+    // bus entries come first, remember which bus ids are ISA
+    let mut isa_buses = [false; 256];
+    let entry_count = read_u16(config + 34);
+    let mut entry = config + 44;
+    for _ in 0..entry_count {
+        match read_u8(entry) {
+            // processor, bit 0 of the flags: enabled
+            0 => {
+                if read_u8(entry + 3) & 1 == 1 {
+                    info.add_processor(read_u8(entry + 1));
+                }
+                entry += 20;
+            }
+            // bus, with a space padded type string
+            1 => {
+                let bus_type: [u8; 6] = unsafe { read_phys(entry + 2, physical_memory_offset) };
+                if &bus_type == b"ISA   " {
+                    isa_buses[read_u8(entry + 1) as usize] = true;
+                }
+                entry += 8;
+            }
+            // I/O APIC, bit 0 of the flags: usable. The table has no GSI base.
+            2 => {
+                if read_u8(entry + 3) & 1 == 1 {
+                    info.add_io_apic(IoApicInfo {
+                        id: read_u8(entry + 1),
+                        address: read_u32(entry + 4) as u64,
+                        gsi_base: None,
+                    });
+                }
+                entry += 8;
+            }
+            // I/O interrupt assignment of a vectored interrupt (type 0) from an ISA bus
+            3 => {
+                let source = read_u8(entry + 5);
+                if read_u8(entry + 1) == 0 && isa_buses[read_u8(entry + 4) as usize] && source < 16 {
+                    info.add_override(InterruptOverride {
+                        source,
+                        gsi: read_u8(entry + 7) as u32,
+                        flags: read_u16(entry + 2),
+                        io_apic: Some(read_u8(entry + 6)),
+                    });
+                }
+                entry += 8;
+            }
+            _ => entry += 8,
+        }
+    }
+    Some(info)
+}
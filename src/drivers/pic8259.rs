@@ -30,19 +30,3 @@ pub const SECONDARY_PIC_OFFSET: u8 = PRIMARY_PIC_OFFSET + 8;
 pub static PICS: spin::Mutex<ChainedPics> = spin::Mutex::new(unsafe {
     ChainedPics::new(PRIMARY_PIC_OFFSET, SECONDARY_PIC_OFFSET)
 });
-
-#[derive(Debug, Clone, Copy)]
-#[repr(u8)]
-pub enum InterruptIndex {
-    Timer = PRIMARY_PIC_OFFSET,
-    Keyboard,
-}
-
-impl InterruptIndex {
-    pub fn as_u8(self) -> u8 {
-        self as u8
-    }
-    pub fn as_usize(self) -> usize {
-        usize::from(self as u8)
-    }
-}
\ No newline at end of file
@@ -0,0 +1,72 @@
+/*
+ * 8253/8254 PIT (Programmable Interval Timer)
+ *
+ * Channel 0 is wired to IRQ 0. Its input clock runs at 1.193182 MHz and is
+ * divided by a 16-bit reload value, so the interrupt rate can be set between
+ * ~18.2 Hz (divisor 65536, the power-on default) and several hundred kHz.
+ */
+use core::sync::atomic::{AtomicU32, Ordering};
+use x86_64::instructions::port::Port;
+
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+const CHANNEL0_DATA: u16 = 0x40;
+const CHANNEL2_DATA: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+// channel 0, access lobyte/hibyte, mode 2 (rate generator), binary
+const CHANNEL0_RATE_GENERATOR: u8 = 0b0011_0100;
+// channel 2, access lobyte/hibyte, mode 0 (interrupt on terminal count), binary
+const CHANNEL2_ONE_SHOT: u8 = 0b1011_0000;
+/* port 0x61: bit 0 gates channel 2, bit 1 drives the speaker, bit 5 is the channel 2 output */
+const SPEAKER_PORT: u16 = 0x61;
+const CHANNEL2_GATE: u8 = 1 << 0;
+const SPEAKER_ENABLE: u8 = 1 << 1;
+const CHANNEL2_OUT: u8 = 1 << 5;
+
+// 0 stands for 65536 like in the reload register
+static DIVISOR: AtomicU32 = AtomicU32::new(65536);
+
+/// Program channel 0 to fire `hz` times per second.
+/// Returns the frequency actually used, which is rounded to a whole divisor.
+/// 0 is treated as the slowest possible rate.
+pub fn set_frequency(hz: u32) -> u32 {
+    let divisor = (PIT_FREQUENCY / hz.max(1) as u64).max(1).min(65536) as u32;
+    let mut command = Port::<u8>::new(COMMAND);
+    let mut data = Port::<u8>::new(CHANNEL0_DATA);
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        command.write(CHANNEL0_RATE_GENERATOR);
+        data.write(divisor as u8);
+        data.write((divisor >> 8) as u8);
+    });
+    DIVISOR.store(divisor, Ordering::Relaxed);
+    (PIT_FREQUENCY / divisor as u64) as u32
+}
+
+/// The current reload value of channel 0
+pub fn divisor() -> u32 {
+    DIVISOR.load(Ordering::Relaxed)
+}
+
+/// Busy wait `ms` milliseconds (at most 54) with channel 2, without interrupts.
+/// `during` is called right after the countdown started and right after it
+/// finished, which is used to calibrate other clocks against the PIT.
+pub fn one_shot_wait(ms: u64, mut during: impl FnMut()) {
+    let count = (PIT_FREQUENCY * ms / 1000).min(65535) as u16;
+    let mut speaker = Port::<u8>::new(SPEAKER_PORT);
+    let mut command = Port::<u8>::new(COMMAND);
+    let mut data = Port::<u8>::new(CHANNEL2_DATA);
+    unsafe {
+        // open the gate of channel 2 but keep the speaker quiet
+        let value = speaker.read();
+        speaker.write((value & !SPEAKER_ENABLE) | CHANNEL2_GATE);
+        command.write(CHANNEL2_ONE_SHOT);
+        data.write(count as u8);
+        data.write((count >> 8) as u8);
+        during();
+        while speaker.read() & CHANNEL2_OUT == 0 {
+            core::sync::atomic::spin_loop_hint();
+        }
+        during();
+        speaker.write(value);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,172 @@
+/*
+ * CMOS RTC (Real Time Clock)
+ *
+ * The RTC registers are read through the CMOS index port 0x70 and data port
+ * 0x71. Depending on status register B the values are BCD or binary, and the
+ * hour is in 12 hour (bit 7 set for PM) or 24 hour format. The registers must
+ * not be read while an update is in progress, so they are read until two
+ * consecutive reads agree.
+ */
+use crate::interrupts::irq::{self, IrqReturn};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::InterruptStackFrame;
+
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+// setting bit 7 of the index disables NMIs while we access the CMOS, the
+// index is written again without it afterwards to enable them again
+const NMI_DISABLE: u8 = 0x80;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_CENTURY: u8 = 0x32;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+const REG_STATUS_C: u8 = 0x0c;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+const STATUS_B_PERIODIC_INTERRUPT: u8 = 1 << 6;
+const STATUS_C_PERIODIC_FLAG: u8 = 1 << 6;
+const HOUR_PM: u8 = 1 << 7;
+
+const RTC_IRQ: u8 = 8;
+
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Date and time as stored in the RTC, always in UTC for our purpose
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RtcTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+fn read_register(reg: u8) -> u8 {
+    let mut index = Port::<u8>::new(CMOS_INDEX);
+    unsafe {
+        index.write(NMI_DISABLE | reg);
+        let value = Port::<u8>::new(CMOS_DATA).read();
+        index.write(reg);
+        value
+    }
+}
+
+fn write_register(reg: u8, value: u8) {
+    let mut index = Port::<u8>::new(CMOS_INDEX);
+    unsafe {
+        index.write(NMI_DISABLE | reg);
+        Port::<u8>::new(CMOS_DATA).write(value);
+        index.write(reg);
+    }
+}
+
+fn update_in_progress() -> bool {
+    read_register(REG_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0
+}
+
+/// Raw register values: seconds, minutes, hours, day, month, year, century
+fn read_raw() -> [u8; 7] {
+    while update_in_progress() {
+        core::sync::atomic::spin_loop_hint();
+    }
+    [
+        read_register(REG_SECONDS),
+        read_register(REG_MINUTES),
+        read_register(REG_HOURS),
+        read_register(REG_DAY),
+        read_register(REG_MONTH),
+        read_register(REG_YEAR),
+        read_register(REG_CENTURY),
+    ]
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value & 0x0f) + (value >> 4) * 10
+}
+
+/// Read the current date and time from the RTC
+pub fn read_time() -> RtcTime {
+    let (raw, status_b) = interrupts::without_interrupts(|| {
+        let mut raw = read_raw();
+        loop {
+            let again = read_raw();
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        (raw, read_register(REG_STATUS_B))
+    });
+
+    let [mut second, mut minute, mut hour, mut day, mut month, mut year, mut century] = raw;
+    let pm = hour & HOUR_PM != 0;
+    hour &= !HOUR_PM;
+    if status_b & STATUS_B_BINARY == 0 {
+        second = bcd_to_binary(second);
+        minute = bcd_to_binary(minute);
+        hour = bcd_to_binary(hour);
+        day = bcd_to_binary(day);
+        month = bcd_to_binary(month);
+        year = bcd_to_binary(year);
+        century = bcd_to_binary(century);
+    }
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12 AM is midnight and 12 PM is noon
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+    // the century register is not standard, assume 20xx when it is missing
+    let century = if century >= 19 && century <= 99 { century as u16 } else { 20 };
+
+    RtcTime {
+        year: century * 100 + year as u16,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+    }
+}
+
+fn periodic_interrupt_handler(_stack_frame: &mut InterruptStackFrame) -> IrqReturn {
+    // the RTC raises no further interrupt until status register C is read
+    if read_register(REG_STATUS_C) & STATUS_C_PERIODIC_FLAG == 0 {
+        return IrqReturn::NotHandled;
+    }
+    PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+    IrqReturn::Handled
+}
+
+/// Enable the RTC periodic interrupt on IRQ 8 at 32768 >> (rate - 1) Hz.
+/// `rate` must be between 3 (8192 Hz) and 15 (2 Hz).
+#[allow(dead_code)]
+pub fn enable_periodic_interrupt(rate: u8) {
+    assert!(rate >= 3 && rate <= 15);
+    irq::register(RTC_IRQ, periodic_interrupt_handler).expect("failed to register the RTC IRQ");
+    interrupts::without_interrupts(|| {
+        let status_a = read_register(REG_STATUS_A);
+        write_register(REG_STATUS_A, (status_a & 0xf0) | rate);
+        let status_b = read_register(REG_STATUS_B);
+        write_register(REG_STATUS_B, status_b | STATUS_B_PERIODIC_INTERRUPT);
+        read_register(REG_STATUS_C);
+    });
+}
+
+/// Number of RTC periodic interrupts so far
+#[allow(dead_code)]
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::Relaxed)
+}
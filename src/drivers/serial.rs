@@ -3,6 +3,9 @@ use spin::Mutex;
 use uart_16550::SerialPort;
 use core::fmt;
 
+// how long `break_lock` waits for a holder on another CPU
+const BREAK_LOCK_SPINS: usize = 10_000_000;
+
 lazy_static! {
     pub static ref SERIAL1: Mutex<SerialPort> = {
         let mut serial_port = unsafe {
@@ -26,3 +29,16 @@ pub fn _print(args: fmt::Arguments) {
         SERIAL1.lock().write_fmt(args).expect("Printing to serial failed");
     });
 }
+
+/// Make SERIAL1 usable from a context that may have interrupted its holder,
+/// like an NMI. A holder on another CPU gets a moment to finish its line, then
+/// the lock is broken.
+pub fn break_lock() {
+    for _ in 0..BREAK_LOCK_SPINS {
+        if SERIAL1.try_lock().is_some() {
+            return;
+        }
+        core::sync::atomic::spin_loop_hint();
+    }
+    unsafe { SERIAL1.force_unlock() };
+}
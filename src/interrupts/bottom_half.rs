@@ -0,0 +1,97 @@
+/*
+ * Deferred interrupt work.
+ *
+ * Interrupt handlers do the minimum with the device and postpone the rest:
+ *   - bottom halves: one function per IRQ line, marked pending by
+ *     `schedule` and run once no matter how often it was scheduled
+ *   - the workqueue: arbitrary closures run in FIFO order
+ * Both run in `irq_exit`, after the interrupt controller got its EOI and with
+ * interrupts enabled. Nested interrupts only mark work pending, the outermost
+ * one runs it. Every CPU runs the pending work on its own way out, the
+ * pending bits and the workqueue are shared.
+ */
+use super::irq::IRQ_LINES;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::mem;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub type BottomHalf = fn();
+pub type Work = Box<dyn FnOnce() + Send>;
+
+static PENDING: AtomicU32 = AtomicU32::new(0);
+static BOTTOM_HALVES: Mutex<[Option<BottomHalf>; IRQ_LINES]> = Mutex::new([None; IRQ_LINES]);
+// a Vec does not allocate until work is queued, which is safe before the heap exists
+static WORKQUEUE: Mutex<Vec<Work>> = Mutex::new(Vec::new());
+
+crate::percpu! { static IN_BOTTOM_HALF: AtomicBool = AtomicBool::new(false); }
+
+/// Set the bottom half of IRQ `line`
+pub fn register(line: u8, bottom_half: BottomHalf) {
+    interrupts::without_interrupts(|| {
+        BOTTOM_HALVES.lock()[line as usize] = Some(bottom_half);
+    });
+}
+
+/// Mark the bottom half of IRQ `line` pending, called from its handler
+pub fn schedule(line: u8) {
+    PENDING.fetch_or(1 << line, Ordering::Release);
+}
+
+/// Queue `work` to run after the current (or next) interrupt
+#[allow(dead_code)]
+pub fn queue_work(work: impl FnOnce() + Send + 'static) {
+    interrupts::without_interrupts(|| {
+        WORKQUEUE.lock().push(Box::new(work));
+    });
+}
+
+fn has_work() -> bool {
+    PENDING.load(Ordering::Acquire) != 0
+        || !interrupts::without_interrupts(|| WORKQUEUE.lock().is_empty())
+}
+
+/// Run all pending bottom halves and queued work. Returns whether anything ran.
+pub fn run_pending() -> bool {
+    let mut ran = false;
+    loop {
+        let pending = PENDING.swap(0, Ordering::Acquire);
+        let bottom_halves = interrupts::without_interrupts(|| *BOTTOM_HALVES.lock());
+        for line in 0..IRQ_LINES {
+            if pending & (1 << line) != 0 {
+                if let Some(bottom_half) = bottom_halves[line] {
+                    bottom_half();
+                }
+            }
+        }
+
+        let work = interrupts::without_interrupts(|| mem::replace(&mut *WORKQUEUE.lock(), Vec::new()));
+        if pending == 0 && work.is_empty() {
+            return ran;
+        }
+        for work in work {
+            work();
+        }
+        ran = true;
+    }
+}
+
+/// Whether this CPU is running bottom halves right now, possibly interrupted
+pub fn in_bottom_half() -> bool {
+    IN_BOTTOM_HALF.get().load(Ordering::Relaxed)
+}
+
+/// Called on the way out of an interrupt, with interrupts disabled and the
+/// EOI already sent
+pub fn irq_exit() {
+    let in_bottom_half = IN_BOTTOM_HALF.get();
+    if !has_work() || in_bottom_half.swap(true, Ordering::Acquire) {
+        return;
+    }
+    interrupts::enable();
+    run_pending();
+    interrupts::disable();
+    in_bottom_half.store(false, Ordering::Release);
+}
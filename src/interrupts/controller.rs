@@ -0,0 +1,103 @@
+/*
+ * The active interrupt controller.
+ *
+ * The kernel boots with the legacy 8259 PICs. If the firmware describes an
+ * I/O APIC, `switch_to_apic` masks the PICs, routes the legacy IRQs through the
+ * I/O APIC redirection entries and from then on EOIs go to the local APIC.
+ * Without an APIC the PICs stay in use.
+ */
+use crate::drivers::apic::{self, LocalApic};
+use super::irq;
+use crate::drivers::pic8259::PICS;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::port::Port;
+
+static APIC_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+pub fn apic_active() -> bool {
+    APIC_ACTIVE.load(Ordering::Relaxed)
+}
+
+/// Signal the end of the interrupt with the given vector to the active controller
+pub fn end_of_interrupt(vector: u8) {
+    if apic_active() {
+        LocalApic::get().unwrap().end_of_interrupt();
+    } else {
+        unsafe {
+            PICS.lock().notify_end_of_interrupt(vector);
+        }
+    }
+}
+
+const PRIMARY_PIC_DATA: u16 = 0x21;
+const SECONDARY_PIC_DATA: u16 = 0xa1;
+// IRQ 2 connects the secondary PIC to the primary one
+const CASCADE_IRQ: u8 = 2;
+
+/// Initialize the 8259 PICs with every line masked except the cascade.
+/// Lines are unmasked when a handler is registered for them.
+pub fn init_pics() {
+    unsafe {
+        PICS.lock().initialize();
+        Port::<u8>::new(PRIMARY_PIC_DATA).write(!(1 << CASCADE_IRQ));
+        Port::<u8>::new(SECONDARY_PIC_DATA).write(0xff);
+    }
+}
+
+/// Mask all lines of both 8259 PICs
+fn disable_pics() {
+    unsafe {
+        Port::<u8>::new(PRIMARY_PIC_DATA).write(0xff);
+        Port::<u8>::new(SECONDARY_PIC_DATA).write(0xff);
+    }
+}
+
+/// Set or clear the bit of `line` in the interrupt mask register of its PIC
+fn set_pic_masked(line: u8, masked: bool) {
+    let (port, bit) = if line < 8 {
+        (PRIMARY_PIC_DATA, line)
+    } else {
+        (SECONDARY_PIC_DATA, line - 8)
+    };
+    let mut port = Port::<u8>::new(port);
+    unsafe {
+        let mask = port.read();
+        port.write(if masked { mask | (1 << bit) } else { mask & !(1 << bit) });
+    }
+}
+
+/// Stop IRQ `line` from being delivered
+pub fn mask_irq(line: u8) {
+    if apic_active() {
+        apic::set_isa_irq_masked(line, true);
+    } else {
+        set_pic_masked(line, true);
+    }
+}
+
+/// Deliver IRQ `line` again
+pub fn unmask_irq(line: u8) {
+    if apic_active() {
+        apic::set_isa_irq_masked(line, false);
+    } else {
+        set_pic_masked(line, false);
+    }
+}
+
+/// Use the local APIC and the I/O APIC instead of the PICs.
+/// Must be called after `apic::init` found and mapped the APICs.
+pub fn switch_to_apic() {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        disable_pics();
+        let bsp = LocalApic::get().unwrap().id();
+        // route every legacy line, only those with handlers are unmasked
+        for line in 0..irq::IRQ_LINES as u8 {
+            if line != CASCADE_IRQ {
+                apic::route_isa_irq(line, irq::vector(line), bsp, !irq::has_handlers(line));
+            }
+        }
+        APIC_ACTIVE.store(true, Ordering::Relaxed);
+    });
+    serial_println!("interrupts: using the I/O APIC, 8259 PICs masked");
+}
+
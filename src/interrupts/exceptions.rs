@@ -0,0 +1,284 @@
+/*
+ * Handlers for the architectural CPU exceptions (vectors 0-31).
+ *
+ * Faults that cannot be recovered from are routed to `fatal_fault`, which
+ * prints the decoded error code, the interrupt stack frame and the general
+ * purpose registers before panicking. Their vectors point to naked entry
+ * stubs that save the registers before any compiled code can change them.
+ * Breakpoint and debug are traps and simply return. Device not available
+ * (#NM) is handled by the task::fpu module, which loads the FPU state of the
+ * current thread.
+ */
+use super::{gdt, stats};
+use core::fmt;
+use x86_64::structures::idt::{Entry, HandlerFunc, InterruptDescriptorTable, InterruptStackFrame};
+
+/// General purpose registers of the interrupted code.
+///
+/// The entry stubs below push them before any compiled code runs, so the
+/// handlers see the exact values at the time of the exception.
+#[repr(C)]
+pub struct Registers {
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rbp: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "rax={:#018x} rbx={:#018x} rcx={:#018x}", self.rax, self.rbx, self.rcx)?;
+        writeln!(f, "rdx={:#018x} rsi={:#018x} rdi={:#018x}", self.rdx, self.rsi, self.rdi)?;
+        writeln!(f, "rbp={:#018x} r8 ={:#018x} r9 ={:#018x}", self.rbp, self.r8, self.r9)?;
+        writeln!(f, "r10={:#018x} r11={:#018x} r12={:#018x}", self.r10, self.r11, self.r12)?;
+        write!(f, "r13={:#018x} r14={:#018x} r15={:#018x}", self.r13, self.r14, self.r15)
+    }
+}
+
+/// Error code of #TS, #NP, #SS and #GP: the segment selector that caused the fault
+pub struct SelectorErrorCode(pub u64);
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 == 0 {
+            return write!(f, "{:#x} (not segment related)", self.0);
+        }
+        let table = match (self.0 >> 1) & 0b11 {
+            0b00 => "GDT",
+            0b10 => "LDT",
+            _ => "IDT",
+        };
+        write!(
+            f,
+            "{:#x} (index {} in {}, external: {})",
+            self.0,
+            (self.0 >> 3) & 0x1fff,
+            table,
+            self.0 & 1 == 1
+        )
+    }
+}
+
+/// Report an unrecoverable exception and panic
+pub fn fatal_fault(
+    name: &str,
+    vector: u8,
+    stack_frame: &InterruptStackFrame,
+    error_code: Option<&dyn fmt::Display>,
+    regs: &Registers,
+) -> ! {
+    stats::record(vector, 0);
+    serial_println!("==================================================");
+    serial_println!("EXCEPTION: {} (vector {})", name, vector);
+    if let Some(error_code) = error_code {
+        serial_println!("Error Code: {}", error_code);
+    }
+    serial_println!("{:#?}", stack_frame);
+    serial_println!("{}", regs);
+    panic!("EXCEPTION: {} at {:?}", name, stack_frame.instruction_pointer);
+}
+
+/*
+ * Common part of all entry stubs. On entry the stack holds:
+ *     [rsp]       handler to call
+ *     [rsp + 8]   error code, pushed by the CPU or as 0 by the stub
+ *     [rsp + 16]  interrupt stack frame
+ * The CPU aligned the stack to 16 bytes before pushing the frame, so after
+ * the 15 register pushes rsp is aligned again for the call.
+ */
+#[naked]
+unsafe extern "C" fn exception_common() {
+    asm!(
+        "push r15",
+        "push r14",
+        "push r13",
+        "push r12",
+        "push r11",
+        "push r10",
+        "push r9",
+        "push r8",
+        "push rbp",
+        "push rdi",
+        "push rsi",
+        "push rdx",
+        "push rcx",
+        "push rbx",
+        "push rax",
+        "cld",
+        "mov rdi, rsp",
+        "lea rsi, [rsp + 136]",
+        "mov rdx, [rsp + 128]",
+        "call qword ptr [rsp + 120]",
+        "pop rax",
+        "pop rbx",
+        "pop rcx",
+        "pop rdx",
+        "pop rsi",
+        "pop rdi",
+        "pop rbp",
+        "pop r8",
+        "pop r9",
+        "pop r10",
+        "pop r11",
+        "pop r12",
+        "pop r13",
+        "pop r14",
+        "pop r15",
+        // drop the handler and the error code
+        "add rsp, 16",
+        "iretq",
+        options(noreturn)
+    );
+}
+
+/// Define an entry stub `$stub` that saves the registers and calls `$handler`,
+/// an `extern "C" fn(&mut Registers, &mut InterruptStackFrame, u64)` taking
+/// the error code (0 for exceptions without one)
+macro_rules! entry_stub {
+    ($stub:ident, $handler:path) => {
+        #[naked]
+        unsafe extern "C" fn $stub() {
+            asm!(
+                "push 0",
+                // push the handler without touching a register
+                "push rax",
+                "lea rax, [rip + {handler}]",
+                "xchg rax, [rsp]",
+                "jmp {common}",
+                handler = sym $handler,
+                common = sym exception_common,
+                options(noreturn)
+            );
+        }
+    };
+    ($stub:ident, $handler:path, error_code) => {
+        #[naked]
+        unsafe extern "C" fn $stub() {
+            asm!(
+                "push rax",
+                "lea rax, [rip + {handler}]",
+                "xchg rax, [rsp]",
+                "jmp {common}",
+                handler = sym $handler,
+                common = sym exception_common,
+                options(noreturn)
+            );
+        }
+    };
+}
+
+/// Turn an entry stub into the handler type of an IDT entry. The entry types
+/// of the x86_64 crate only accept x86-interrupt functions, but all they keep
+/// is the address.
+pub unsafe fn stub_handler<F>(stub: unsafe extern "C" fn()) -> F {
+    assert_eq!(core::mem::size_of::<F>(), core::mem::size_of::<u64>());
+    core::mem::transmute_copy(&stub)
+}
+
+macro_rules! fatal_exception {
+    ($stub:ident, $handler:ident, $name:expr, $vector:expr) => {
+        extern "C" fn $handler(regs: &mut Registers, stack_frame: &mut InterruptStackFrame, _error_code: u64) {
+            fatal_fault($name, $vector, stack_frame, None, regs);
+        }
+        entry_stub!($stub, $handler);
+    };
+    ($stub:ident, $handler:ident, $name:expr, $vector:expr, selector) => {
+        extern "C" fn $handler(regs: &mut Registers, stack_frame: &mut InterruptStackFrame, error_code: u64) {
+            let error_code = SelectorErrorCode(error_code);
+            fatal_fault($name, $vector, stack_frame, Some(&error_code), regs);
+        }
+        entry_stub!($stub, $handler, error_code);
+    };
+    ($stub:ident, $handler:ident, $name:expr, $vector:expr, error_code) => {
+        extern "C" fn $handler(regs: &mut Registers, stack_frame: &mut InterruptStackFrame, error_code: u64) {
+            fatal_fault($name, $vector, stack_frame, Some(&error_code), regs);
+        }
+        entry_stub!($stub, $handler, error_code);
+    };
+}
+
+fatal_exception!(divide_error_stub, divide_error_handler, "DIVIDE ERROR", 0);
+fatal_exception!(overflow_stub, overflow_handler, "OVERFLOW", 4);
+fatal_exception!(bound_range_exceeded_stub, bound_range_exceeded_handler, "BOUND RANGE EXCEEDED", 5);
+fatal_exception!(invalid_opcode_stub, invalid_opcode_handler, "INVALID OPCODE", 6);
+// the error code of a double fault is always 0
+fatal_exception!(double_fault_stub, double_fault_handler, "DOUBLE FAULT", 8, error_code);
+fatal_exception!(invalid_tss_stub, invalid_tss_handler, "INVALID TSS", 10, selector);
+fatal_exception!(segment_not_present_stub, segment_not_present_handler, "SEGMENT NOT PRESENT", 11, selector);
+fatal_exception!(stack_segment_fault_stub, stack_segment_fault_handler, "STACK SEGMENT FAULT", 12, selector);
+fatal_exception!(general_protection_fault_stub, general_protection_fault_handler, "GENERAL PROTECTION FAULT", 13, selector);
+fatal_exception!(x87_floating_point_stub, x87_floating_point_handler, "X87 FLOATING POINT", 16);
+fatal_exception!(alignment_check_stub, alignment_check_handler, "ALIGNMENT CHECK", 17, error_code);
+fatal_exception!(machine_check_stub, machine_check_handler, "MACHINE CHECK", 18);
+fatal_exception!(simd_floating_point_stub, simd_floating_point_handler, "SIMD FLOATING POINT", 19);
+fatal_exception!(virtualization_stub, virtualization_handler, "VIRTUALIZATION", 20);
+fatal_exception!(control_protection_stub, control_protection_handler, "CONTROL PROTECTION", 21, error_code);
+fatal_exception!(hypervisor_injection_stub, hypervisor_injection_handler, "HYPERVISOR INJECTION", 28);
+fatal_exception!(vmm_communication_stub, vmm_communication_handler, "VMM COMMUNICATION", 29, error_code);
+fatal_exception!(security_exception_stub, security_exception_handler, "SECURITY EXCEPTION", 30, error_code);
+
+extern "C" fn non_maskable_interrupt_handler(regs: &mut Registers, stack_frame: &mut InterruptStackFrame, _error_code: u64) {
+    // the watcher's NMI was reported, we only get here again with a lockup hook
+    if crate::watchdog::check_hard_lockup(stack_frame) {
+        return;
+    }
+    fatal_fault("NON MASKABLE INTERRUPT", 2, stack_frame, None, regs);
+}
+entry_stub!(non_maskable_interrupt_stub, non_maskable_interrupt_handler);
+entry_stub!(page_fault_stub, super::page_fault::page_fault_handler, error_code);
+
+extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFrame) {
+    stats::measure(3, || println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame));
+}
+
+extern "x86-interrupt" fn debug_handler(stack_frame: &mut InterruptStackFrame) {
+    stats::measure(1, || serial_println!("EXCEPTION: DEBUG\n{:#?}", stack_frame));
+}
+
+/// Install the handlers of all architectural exceptions
+/// IDT entry of exception `vector`. x86_64 0.12 treats 21-29 as reserved and
+/// hides them, but the table is laid out like the one the CPU reads.
+unsafe fn exception_entry(idt: &mut InterruptDescriptorTable, vector: usize) -> &mut Entry<HandlerFunc> {
+    &mut *(idt as *mut InterruptDescriptorTable as *mut Entry<HandlerFunc>).add(vector)
+}
+
+pub fn install_handlers(idt: &mut InterruptDescriptorTable) {
+    unsafe {
+        idt.divide_by_zero.set_handler_fn(stub_handler(divide_error_stub));
+        idt.debug.set_handler_fn(debug_handler);
+        idt.non_maskable_interrupt.set_handler_fn(stub_handler(non_maskable_interrupt_stub));
+        idt.breakpoint.set_handler_fn(breakpoint_handler);
+        idt.overflow.set_handler_fn(stub_handler(overflow_stub));
+        idt.bound_range_exceeded.set_handler_fn(stub_handler(bound_range_exceeded_stub));
+        idt.invalid_opcode.set_handler_fn(stub_handler(invalid_opcode_stub));
+        idt.device_not_available.set_handler_fn(crate::task::fpu::device_not_available_handler);
+        idt.double_fault
+            .set_handler_fn(stub_handler(double_fault_stub))
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        idt.invalid_tss.set_handler_fn(stub_handler(invalid_tss_stub));
+        idt.segment_not_present.set_handler_fn(stub_handler(segment_not_present_stub));
+        idt.stack_segment_fault.set_handler_fn(stub_handler(stack_segment_fault_stub));
+        idt.general_protection_fault.set_handler_fn(stub_handler(general_protection_fault_stub));
+        idt.page_fault.set_handler_fn(stub_handler(page_fault_stub));
+        idt.x87_floating_point.set_handler_fn(stub_handler(x87_floating_point_stub));
+        idt.alignment_check.set_handler_fn(stub_handler(alignment_check_stub));
+        idt.machine_check.set_handler_fn(stub_handler(machine_check_stub));
+        idt.simd_floating_point.set_handler_fn(stub_handler(simd_floating_point_stub));
+        idt.virtualization.set_handler_fn(stub_handler(virtualization_stub));
+        exception_entry(idt, 21).set_handler_fn(stub_handler(control_protection_stub));
+        exception_entry(idt, 28).set_handler_fn(stub_handler(hypervisor_injection_stub));
+        exception_entry(idt, 29).set_handler_fn(stub_handler(vmm_communication_stub));
+        idt.security_exception.set_handler_fn(stub_handler(security_exception_stub));
+    }
+}
@@ -6,6 +6,7 @@
  * exists. It is mostly used for two things: Switching between kernel space and user
  * space, and loading a TSS structure.
  */
+use alloc::boxed::Box;
 use lazy_static::lazy_static;
 use x86_64::structures::gdt::SegmentSelector;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable};
@@ -27,16 +28,16 @@ use x86_64::VirtAddr;
 
 // Use the 0th entry in IST as the double fault stack
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+const DOUBLE_FAULT_STACK_SIZE: usize = 4096 * 5; //20k
 
 lazy_static! {
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5; //20k
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+            static mut STACK: [u8; DOUBLE_FAULT_STACK_SIZE] = [0; DOUBLE_FAULT_STACK_SIZE];
 
             let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
+            let stack_end = stack_start + DOUBLE_FAULT_STACK_SIZE;
             stack_end
         };
         tss
@@ -68,13 +69,38 @@ lazy_static! {
     };
 }
 
-// Initialize GDT and reload the cs segment register and load our TS
-pub fn gdt_init() {
+fn load(gdt: &'static GlobalDescriptorTable, selector: &Selector) {
     use x86_64::instructions::segmentation::set_cs;
     use x86_64::instructions::tables::load_tss;
-    GDT.0.load();
+    gdt.load();
     unsafe {
-        set_cs(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        set_cs(selector.code_selector);
+        load_tss(selector.tss_selector);
     }
 }
+
+// Initialize GDT and reload the cs segment register and load our TS
+pub fn gdt_init() {
+    load(&GDT.0, &GDT.1);
+}
+
+/*
+ * Every application processor needs its own TSS: the TSS descriptor is marked
+ * busy when loaded, and the IST stacks must not be shared. Thus it gets its
+ * own GDT as well. They are needed as long as the CPU runs, so they are
+ * allocated on the heap and leaked.
+ */
+pub fn ap_gdt_init() {
+    let stack = Box::leak(alloc::vec![0u8; DOUBLE_FAULT_STACK_SIZE].into_boxed_slice());
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] =
+        VirtAddr::from_ptr(stack.as_ptr()) + DOUBLE_FAULT_STACK_SIZE;
+    let tss: &'static TaskStateSegment = Box::leak(Box::new(tss));
+
+    let mut gdt = GlobalDescriptorTable::new();
+    let selector = Selector {
+        code_selector: gdt.add_entry(Descriptor::kernel_code_segment()),
+        tss_selector: gdt.add_entry(Descriptor::tss_segment(tss)),
+    };
+    load(Box::leak(Box::new(gdt)), &selector);
+}
@@ -1,9 +1,8 @@
 use lazy_static::lazy_static;
-use super::gdt;
-use super::timer;
-use super::page_fault;
-use crate::drivers::{pic8259, keyboard};
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use super::exceptions;
+use super::irq;
+use super::spurious;
+use x86_64::structures::idt::InterruptDescriptorTable;
 // InterruptDescriptorTable are defined as following
 
 // pub struct InterruptDescriptorTable {
@@ -34,17 +33,15 @@ use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        unsafe {
-            idt.double_fault.set_handler_fn(double_fault_handler).set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
-        }
-        idt.page_fault.set_handler_fn(page_fault::page_fault_handler);
+        exceptions::install_handlers(&mut idt);
         /* 
          * The InterruptDescriptorTable struct implements the IndexMut trait, 
          * so we can access individual entries through array indexing syntax.
+         * Drivers attach to the IRQ lines at runtime through the irq module.
          */
-        idt[pic8259::InterruptIndex::Timer.as_usize()].set_handler_fn(timer::timer_interrupt_handler);
-        idt[pic8259::InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard::keyboard_interrupt_handler);
+        spurious::install_handlers(&mut idt);
+        crate::smp::ipi::install_handlers(&mut idt);
+        irq::install_stubs(&mut idt);
         idt
     };
 }
@@ -53,18 +50,6 @@ pub fn idt_init() {
     IDT.load();
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: &mut InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
-}
-
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: &mut InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    // the error_code in double fault is always 0
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
-}
-
 #[test_case]
 fn test_breakpoint_exception() {
     // invoke a breakpoint exception
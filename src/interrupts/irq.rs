@@ -0,0 +1,149 @@
+/*
+ * Dynamic IRQ handler registration.
+ *
+ * Every legacy IRQ line 0-15 has a fixed stub in the IDT (vectors 32-47) which
+ * calls `dispatch`. Drivers attach handlers to a line at runtime with
+ * `register` and detach them with `unregister`. Several handlers may share a
+ * line, they are all called in registration order. The line is unmasked on
+ * the active interrupt controller when its first handler is registered and
+ * masked again when the last one is removed. The EOI is sent by `dispatch`
+ * after all handlers ran, so handlers must not send one themselves. Longer
+ * work belongs in a bottom half, see the bottom_half module.
+ */
+use super::{bottom_half, controller, spurious, stats};
+use crate::drivers::pic8259::PRIMARY_PIC_OFFSET;
+use crate::smp::percpu;
+use crate::task::scheduler;
+use crate::time::tsc;
+use core::sync::atomic::Ordering;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+pub const IRQ_LINES: usize = 16;
+const MAX_SHARED_HANDLERS: usize = 4;
+
+/// What a handler did with the interrupt. On a shared line, a handler returns
+/// `NotHandled` if its device did not raise the interrupt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqReturn {
+    Handled,
+    NotHandled,
+}
+
+pub type IrqHandler = fn(&mut InterruptStackFrame) -> IrqReturn;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    InvalidLine,
+    TooManyHandlers,
+}
+
+/// Identifies a registered handler, used to unregister it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IrqHandle {
+    line: u8,
+    slot: usize,
+}
+
+type LineHandlers = [Option<IrqHandler>; MAX_SHARED_HANDLERS];
+
+const NO_HANDLERS: Mutex<LineHandlers> = Mutex::new([None; MAX_SHARED_HANDLERS]);
+static HANDLERS: [Mutex<LineHandlers>; IRQ_LINES] = [NO_HANDLERS; IRQ_LINES];
+
+/// The vector on which IRQ `line` arrives
+pub fn vector(line: u8) -> u8 {
+    PRIMARY_PIC_OFFSET + line
+}
+
+/// Attach `handler` to IRQ `line` and unmask the line
+pub fn register(line: u8, handler: IrqHandler) -> Result<IrqHandle, IrqError> {
+    if line as usize >= IRQ_LINES {
+        return Err(IrqError::InvalidLine);
+    }
+    interrupts::without_interrupts(|| {
+        let mut handlers = HANDLERS[line as usize].lock();
+        let slot = handlers
+            .iter()
+            .position(|h| h.is_none())
+            .ok_or(IrqError::TooManyHandlers)?;
+        handlers[slot] = Some(handler);
+        controller::unmask_irq(line);
+        Ok(IrqHandle { line, slot })
+    })
+}
+
+/// Detach a handler, the line is masked if no handler is left
+#[allow(dead_code)]
+pub fn unregister(handle: IrqHandle) {
+    interrupts::without_interrupts(|| {
+        let mut handlers = HANDLERS[handle.line as usize].lock();
+        handlers[handle.slot] = None;
+        if handlers.iter().all(|h| h.is_none()) {
+            controller::mask_irq(handle.line);
+        }
+    });
+}
+
+/// Whether any handler is attached to `line`
+pub fn has_handlers(line: u8) -> bool {
+    HANDLERS[line as usize].lock().iter().any(|h| h.is_some())
+}
+
+/// Whether the current CPU is running an IRQ handler
+pub fn in_interrupt() -> bool {
+    percpu::area().irq_depth.load(Ordering::Relaxed) != 0
+}
+
+/// Run all handlers of `line` and acknowledge the interrupt
+fn dispatch(line: u8, stack_frame: &mut InterruptStackFrame) {
+    let start = tsc::rdtsc();
+    if spurious::check_pic_spurious(line) {
+        stats::record(vector(line), tsc::rdtsc() - start);
+        return;
+    }
+    let depth = &percpu::area().irq_depth;
+    depth.fetch_add(1, Ordering::Relaxed);
+    // copy the handlers so that a handler may (un)register without deadlocking
+    let handlers = *HANDLERS[line as usize].lock();
+    let mut handled = false;
+    for handler in handlers.iter().filter_map(|h| *h) {
+        handled |= handler(stack_frame) == IrqReturn::Handled;
+    }
+    if !handled {
+        serial_println!("irq: nobody handled IRQ {}", line);
+    }
+    controller::end_of_interrupt(vector(line));
+    stats::record(vector(line), tsc::rdtsc() - start);
+    depth.fetch_sub(1, Ordering::Relaxed);
+    bottom_half::irq_exit();
+    // an interrupt that arrived during the bottom halves must not switch away
+    // from the thread running them
+    if !bottom_half::in_bottom_half() {
+        scheduler::preempt_if_needed();
+    }
+}
+
+macro_rules! irq_stubs {
+    ($($line:expr => $stub:ident),*) => {
+        $(
+            extern "x86-interrupt" fn $stub(stack_frame: &mut InterruptStackFrame) {
+                dispatch($line, stack_frame);
+            }
+        )*
+
+        /// Point the vectors of all IRQ lines to their dispatch stubs
+        pub fn install_stubs(idt: &mut InterruptDescriptorTable) {
+            $(
+                idt[vector($line) as usize].set_handler_fn($stub);
+            )*
+        }
+    };
+}
+
+irq_stubs!(
+    0 => irq0, 1 => irq1, 2 => irq2, 3 => irq3,
+    4 => irq4, 5 => irq5, 6 => irq6, 7 => irq7,
+    8 => irq8, 9 => irq9, 10 => irq10, 11 => irq11,
+    12 => irq12, 13 => irq13, 14 => irq14, 15 => irq15
+);
@@ -2,15 +2,47 @@ mod idt;
 mod gdt;
 mod timer;
 mod page_fault;
+mod exceptions;
+pub mod controller;
+pub mod irq;
+pub mod bottom_half;
+pub mod spurious;
+pub mod stats;
 
-use crate::drivers::pic8259;
+use crate::drivers::{apic, keyboard};
+use x86_64::structures::paging::{FrameAllocator, Mapper, Size4KiB};
+use x86_64::VirtAddr;
 
 pub fn interrupt_init() {
     gdt::gdt_init();
     idt::idt_init();
-    unsafe {
-        pic8259::PICS.lock().initialize();
-    }
+    controller::init_pics();
+    timer::init();
+    keyboard::init();
     // execute sti instruction ("set interrupts") to enable external interrupts
     x86_64::instructions::interrupts::enable();
-}
\ No newline at end of file
+}
+
+/// Per-CPU part of the interrupt setup, run by every application processor.
+/// External interrupts are only routed to the BSP.
+pub fn ap_interrupt_init() {
+    gdt::ap_gdt_init();
+    idt::idt_init();
+    if let Some(lapic) = apic::LocalApic::get() {
+        lapic.enable();
+    }
+}
+
+/// Switch from the 8259 PICs to the local APIC and I/O APIC if the machine has them.
+/// The PICs stay in use otherwise.
+pub fn apic_init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    physical_memory_offset: VirtAddr,
+) {
+    if apic::init(mapper, frame_allocator, physical_memory_offset) {
+        controller::switch_to_apic();
+    } else {
+        serial_println!("interrupts: no APIC found, using the 8259 PICs");
+    }
+}
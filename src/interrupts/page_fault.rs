@@ -1,13 +1,22 @@
 use x86_64::structures::idt::{InterruptStackFrame, PageFaultErrorCode};
 use x86_64::registers::control::Cr2;
-use crate::hlt_loop;
+use super::exceptions::{fatal_fault, Registers};
 
-pub extern "x86-interrupt" fn page_fault_handler(stack_frame: &mut InterruptStackFrame, _error_code: PageFaultErrorCode) {
+/// Called by the page fault entry stub in the exceptions module
+pub extern "C" fn page_fault_handler(regs: &mut Registers, stack_frame: &mut InterruptStackFrame, error_code: u64) {
     let fault_addr: u64 = unsafe {
         Cr2::read().as_u64()
     };
     serial_println!("Page fault at {:#x}", fault_addr);
-    serial_println!("Error Code: {:?}", _error_code);
-    serial_println!("{:#?}", stack_frame);
-    hlt_loop();
-}
\ No newline at end of file
+    let error_code = PageFaultErrorCode::from_bits_truncate(error_code);
+    fatal_fault("PAGE FAULT", 14, stack_frame, Some(&DecodedPageFault(error_code)), regs);
+}
+
+/// Print the page fault error code bits by name
+struct DecodedPageFault(PageFaultErrorCode);
+
+impl core::fmt::Display for DecodedPageFault {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        write!(f, "{:#x} {:?}", self.0.bits(), self.0)
+    }
+}
@@ -0,0 +1,142 @@
+/*
+ * Spurious interrupts and unexpected vectors.
+ *
+ * Every IDT slot without a real handler points to a catch-all which logs and
+ * counts the vector, so a misconfigured device cannot end in a double fault.
+ *
+ * The 8259 PICs raise IRQ 7 (primary) or IRQ 15 (secondary) when a request
+ * disappears before the CPU acknowledged it. Such an IRQ is spurious if its bit
+ * is not set in the in-service register: a spurious IRQ 7 must get no EOI, a
+ * spurious IRQ 15 only one to the primary PIC, which did see the cascade.
+ */
+use super::{controller, stats};
+use crate::drivers::apic::{self, LocalApic};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+use x86_64::structures::idt::{HandlerFunc, InterruptDescriptorTable, InterruptStackFrame};
+
+const PRIMARY_PIC_COMMAND: u16 = 0x20;
+const SECONDARY_PIC_COMMAND: u16 = 0xa0;
+// OCW3: the next read of the command port returns the in-service register
+const READ_ISR: u8 = 0x0b;
+const EOI: u8 = 0x20;
+
+/// First vector after the IRQ stubs of the PICs
+const FIRST_UNUSED_VECTOR: usize = 48;
+
+const ZERO: AtomicU64 = AtomicU64::new(0);
+static UNEXPECTED: [AtomicU64; 256] = [ZERO; 256];
+static PIC_SPURIOUS: AtomicU64 = AtomicU64::new(0);
+static APIC_SPURIOUS: AtomicU64 = AtomicU64::new(0);
+
+/// In-service registers of both PICs, primary in the low byte
+fn pic_in_service() -> u16 {
+    unsafe {
+        Port::<u8>::new(PRIMARY_PIC_COMMAND).write(READ_ISR);
+        Port::<u8>::new(SECONDARY_PIC_COMMAND).write(READ_ISR);
+        let primary = Port::<u8>::new(PRIMARY_PIC_COMMAND).read() as u16;
+        let secondary = Port::<u8>::new(SECONDARY_PIC_COMMAND).read() as u16;
+        (secondary << 8) | primary
+    }
+}
+
+/// Check whether IRQ `line` is a spurious PIC interrupt and, if so, send the
+/// EOI it needs. The caller must then neither run handlers nor send an EOI.
+pub fn check_pic_spurious(line: u8) -> bool {
+    if controller::apic_active() || (line != 7 && line != 15) {
+        return false;
+    }
+    if pic_in_service() & (1 << line) != 0 {
+        return false;
+    }
+    PIC_SPURIOUS.fetch_add(1, Ordering::Relaxed);
+    if line == 15 {
+        unsafe {
+            Port::<u8>::new(PRIMARY_PIC_COMMAND).write(EOI);
+        }
+    }
+    true
+}
+
+extern "x86-interrupt" fn unexpected_vector_handler<const VECTOR: u8>(
+    _stack_frame: &mut InterruptStackFrame,
+) {
+    stats::measure(VECTOR, || {
+        let count = UNEXPECTED[VECTOR as usize].fetch_add(1, Ordering::Relaxed) + 1;
+        serial_println!("interrupt: unexpected vector {} (seen {} times)", VECTOR, count);
+        // a device routed through the I/O APIC still expects its EOI
+        if let Some(lapic) = LocalApic::get() {
+            if lapic.is_in_service(VECTOR) {
+                lapic.end_of_interrupt();
+            }
+        }
+    });
+}
+
+// The local APIC does not expect an EOI for its spurious interrupt
+extern "x86-interrupt" fn apic_spurious_handler(_stack_frame: &mut InterruptStackFrame) {
+    APIC_SPURIOUS.fetch_add(1, Ordering::Relaxed);
+    stats::record(apic::SPURIOUS_VECTOR, 0);
+}
+
+macro_rules! vector_row {
+    ($row:expr) => {
+        [
+            unexpected_vector_handler::<{ $row * 16 + 0 }> as HandlerFunc,
+            unexpected_vector_handler::<{ $row * 16 + 1 }>,
+            unexpected_vector_handler::<{ $row * 16 + 2 }>,
+            unexpected_vector_handler::<{ $row * 16 + 3 }>,
+            unexpected_vector_handler::<{ $row * 16 + 4 }>,
+            unexpected_vector_handler::<{ $row * 16 + 5 }>,
+            unexpected_vector_handler::<{ $row * 16 + 6 }>,
+            unexpected_vector_handler::<{ $row * 16 + 7 }>,
+            unexpected_vector_handler::<{ $row * 16 + 8 }>,
+            unexpected_vector_handler::<{ $row * 16 + 9 }>,
+            unexpected_vector_handler::<{ $row * 16 + 10 }>,
+            unexpected_vector_handler::<{ $row * 16 + 11 }>,
+            unexpected_vector_handler::<{ $row * 16 + 12 }>,
+            unexpected_vector_handler::<{ $row * 16 + 13 }>,
+            unexpected_vector_handler::<{ $row * 16 + 14 }>,
+            unexpected_vector_handler::<{ $row * 16 + 15 }>,
+        ]
+    };
+}
+
+/// Catch-all handlers of the vectors 48-255, one row of 16 vectors each
+const UNEXPECTED_HANDLERS: [[HandlerFunc; 16]; 13] = [
+    vector_row!(3),
+    vector_row!(4),
+    vector_row!(5),
+    vector_row!(6),
+    vector_row!(7),
+    vector_row!(8),
+    vector_row!(9),
+    vector_row!(10),
+    vector_row!(11),
+    vector_row!(12),
+    vector_row!(13),
+    vector_row!(14),
+    vector_row!(15),
+];
+
+/// Point every vector from 48 on to the catch-all, and the APIC spurious vector
+/// to its handler. Real handlers are installed on top of these afterwards.
+pub fn install_handlers(idt: &mut InterruptDescriptorTable) {
+    for vector in FIRST_UNUSED_VECTOR..256 {
+        let handler = UNEXPECTED_HANDLERS[vector / 16 - 3][vector % 16];
+        idt[vector].set_handler_fn(handler);
+    }
+    idt[apic::SPURIOUS_VECTOR as usize].set_handler_fn(apic_spurious_handler);
+}
+
+/// Number of spurious interrupts from the PICs and the local APIC
+#[allow(dead_code)]
+pub fn spurious_counts() -> (u64, u64) {
+    (PIC_SPURIOUS.load(Ordering::Relaxed), APIC_SPURIOUS.load(Ordering::Relaxed))
+}
+
+/// How often `vector` arrived without a handler
+#[allow(dead_code)]
+pub fn unexpected_count(vector: u8) -> u64 {
+    UNEXPECTED[vector as usize].load(Ordering::Relaxed)
+}
@@ -0,0 +1,114 @@
+/*
+ * Per-vector interrupt statistics.
+ *
+ * Every exception and interrupt is counted per vector and per CPU, together
+ * with the TSC cycles spent in its handler. `print_interrupts` dumps the
+ * counters like /proc/interrupts.
+ */
+use crate::drivers::apic::MAX_CPUS;
+use crate::smp::{self, ipi};
+use crate::time::tsc;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+const VECTORS: usize = 256;
+
+const ZERO: AtomicU64 = AtomicU64::new(0);
+const ZERO_ROW: [AtomicU64; VECTORS] = [ZERO; VECTORS];
+static COUNTS: [[AtomicU64; VECTORS]; MAX_CPUS] = [ZERO_ROW; MAX_CPUS];
+static CYCLES: [[AtomicU64; VECTORS]; MAX_CPUS] = [ZERO_ROW; MAX_CPUS];
+
+/// Counters of one vector on one CPU
+#[derive(Debug, Clone, Copy, Default)]
+pub struct VectorStats {
+    pub count: u64,
+    pub cycles: u64,
+}
+
+/// Count one occurrence of `vector` which took `cycles` TSC cycles to handle
+pub fn record(vector: u8, cycles: u64) {
+    let cpu = smp::current_cpu();
+    COUNTS[cpu][vector as usize].fetch_add(1, Ordering::Relaxed);
+    CYCLES[cpu][vector as usize].fetch_add(cycles, Ordering::Relaxed);
+}
+
+/// Run `handler` for `vector` and account the time spent in it
+#[inline(always)]
+pub fn measure<R>(vector: u8, handler: impl FnOnce() -> R) -> R {
+    let start = tsc::rdtsc();
+    let result = handler();
+    record(vector, tsc::rdtsc() - start);
+    result
+}
+
+pub fn get(cpu: usize, vector: u8) -> VectorStats {
+    VectorStats {
+        count: COUNTS[cpu][vector as usize].load(Ordering::Relaxed),
+        cycles: CYCLES[cpu][vector as usize].load(Ordering::Relaxed),
+    }
+}
+
+/// Sum of the counters of `vector` over all CPUs
+pub fn total(vector: u8) -> VectorStats {
+    (0..MAX_CPUS).fold(VectorStats::default(), |sum, cpu| {
+        let stats = get(cpu, vector);
+        VectorStats {
+            count: sum.count + stats.count,
+            cycles: sum.cycles + stats.cycles,
+        }
+    })
+}
+
+/// A short name for `vector`
+pub fn vector_name(vector: u8) -> &'static str {
+    const EXCEPTIONS: [&str; 32] = [
+        "divide error", "debug", "NMI", "breakpoint", "overflow", "bound range",
+        "invalid opcode", "device n/a", "double fault", "coproc overrun", "invalid TSS",
+        "segment n/p", "stack fault", "GP fault", "page fault", "reserved",
+        "x87 FPU", "alignment", "machine check", "SIMD FPU", "virtualization",
+        "control protection", "reserved", "reserved", "reserved", "reserved", "reserved",
+        "reserved", "hypervisor injection", "VMM communication", "security", "reserved",
+    ];
+    const IRQS: [&str; 16] = [
+        "IRQ0 timer", "IRQ1 keyboard", "IRQ2 cascade", "IRQ3", "IRQ4", "IRQ5", "IRQ6",
+        "IRQ7", "IRQ8 rtc", "IRQ9", "IRQ10", "IRQ11", "IRQ12", "IRQ13", "IRQ14", "IRQ15",
+    ];
+    match vector {
+        0..=31 => EXCEPTIONS[vector as usize],
+        32..=47 => IRQS[vector as usize - 32],
+        crate::drivers::apic::SPURIOUS_VECTOR => "APIC spurious",
+        ipi::RESCHEDULE_VECTOR => "IPI reschedule",
+        ipi::CALL_FUNCTION_VECTOR => "IPI call function",
+        ipi::TLB_SHOOTDOWN_VECTOR => "IPI TLB shootdown",
+        _ => "unexpected",
+    }
+}
+
+/// Print every vector that fired at least once, like /proc/interrupts
+#[allow(dead_code)]
+pub fn print_interrupts() {
+    let cpus = smp::cpu_count();
+
+    serial_print!("vec ");
+    for cpu in 0..cpus {
+        serial_print!("{:>9}{:<3}", "CPU", cpu);
+    }
+    serial_println!("{:>14}  name", "avg cycles");
+    for vector in 0..VECTORS {
+        let total = total(vector as u8);
+        if total.count == 0 {
+            continue;
+        }
+        serial_print!("{:>3} ", vector);
+        for cpu in 0..cpus {
+            serial_print!("{:>12}", get(cpu, vector as u8).count);
+        }
+        serial_println!("{:>14}  {}", total.cycles / total.count, vector_name(vector as u8));
+    }
+}
+
+#[test_case]
+fn breakpoint_is_counted() {
+    let before = total(3).count;
+    x86_64::instructions::interrupts::int3();
+    assert_eq!(total(3).count, before + 1);
+}
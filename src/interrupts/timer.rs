@@ -1,11 +1,28 @@
 use x86_64::structures::idt::InterruptStackFrame;
-use crate::drivers::pic8259::{PICS, InterruptIndex};
+use super::bottom_half;
+use super::irq::{self, IrqReturn};
+use crate::task::scheduler;
+use crate::time::timer_wheel;
+use crate::watchdog;
 
-pub extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: &mut InterruptStackFrame) {
-    //serial_print!(".");
+const TIMER_IRQ: u8 = 0;
+
+pub fn init() {
+    crate::time::init();
+    bottom_half::register(TIMER_IRQ, timer_wheel::run_expired);
+    irq::register(TIMER_IRQ, timer_interrupt_handler).expect("failed to register the timer IRQ");
+}
 
-    // We have to send a EOI(end of interrup) to pic.
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+pub fn timer_interrupt_handler(stack_frame: &mut InterruptStackFrame) -> IrqReturn {
+    //serial_print!(".");
+    crate::time::tick();
+    scheduler::tick();
+    watchdog::check_soft_lockup(stack_frame);
+    // the wheel advances and expired timers run in the bottom half, after the EOI
+    if timer_wheel::has_expired() {
+        bottom_half::schedule(TIMER_IRQ);
     }
-}
\ No newline at end of file
+
+    // The EOI(end of interrupt) is sent by the irq dispatcher.
+    IrqReturn::Handled
+}
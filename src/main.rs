@@ -8,14 +8,26 @@
 #![feature(alloc_error_handler)]
 #![feature(const_mut_refs)]
 #![feature(const_in_array_repeat_expressions)]
+#![feature(allocator_api)]
+#![feature(asm)]
+#![feature(min_const_generics)]
+#![feature(naked_functions)]
+#![feature(wake_trait)]
+#![feature(global_asm)]
 
 #[macro_use]
 mod console;
+mod backtrace;
 mod drivers;
 mod interrupts;
 mod mm;
 mod panic;
+mod smp;
+mod sync;
+mod task;
 mod tests;
+mod time;
+mod watchdog;
 
 extern crate alloc;
 use bootloader::{entry_point, BootInfo};
@@ -30,7 +42,9 @@ pub fn kernel_main(bootinfo: &'static BootInfo) -> ! {
     println!("Tour of rust begins here!");
     serial_println!("Version: {}.{}", 1, 0);
 
+    smp::percpu::init(0);
     interrupts::interrupt_init();
+    task::fpu::init();
     // invoke a breakpoint exception
     x86_64::instructions::interrupts::int3(); // new
 
@@ -38,14 +52,32 @@ pub fn kernel_main(bootinfo: &'static BootInfo) -> ! {
     let mut mapper = unsafe { page_table::init(phys_mem_offset) };
     let mut frame_allocator =
         unsafe { allocator::BootInfoFrameAllocator::init(&bootinfo.memory_map) };
+    #[cfg(feature = "dump_memory")]
+    {
+        allocator::dump_memory_map(&bootinfo.memory_map);
+        unsafe { page_table::dump_address_space(phys_mem_offset) };
+    }
+    #[cfg(feature = "memtest")]
+    unsafe {
+        mm::memtest::run(&bootinfo.memory_map, phys_mem_offset, &mut frame_allocator);
+    }
+    let trampoline_frame = smp::reserve_trampoline_frame(&mut frame_allocator);
     heap_allocator::init_kernel_heap(&mut mapper, &mut frame_allocator)
         .expect("heap initialization failed");
+    interrupts::apic_init(&mut mapper, &mut frame_allocator, phys_mem_offset);
+    time::clocksource::init(&mut mapper, &mut frame_allocator, phys_mem_offset);
+    smp::init(&mut mapper, &mut frame_allocator, phys_mem_offset, trampoline_frame);
+    task::init();
+    watchdog::init(time::Duration::from_secs(10));
     serial_println!("It did not crash!");
 
     #[cfg(test)]
     test_main();
 
-    hlt_loop();
+    // keyboard input is handled by an async task from now on
+    let mut executor = task::executor::Executor::new();
+    executor.spawn(task::executor::Task::new(drivers::keyboard::print_keypresses()));
+    executor.run();
 }
 
 pub fn hlt_loop() -> ! {
@@ -53,3 +85,17 @@ pub fn hlt_loop() -> ! {
         x86_64::instructions::hlt();
     }
 }
+
+/// Reset the machine by pulsing the CPU reset line of the 8042 keyboard
+/// controller
+pub fn reboot() -> ! {
+    use x86_64::instructions::port::Port;
+    x86_64::instructions::interrupts::disable();
+    unsafe {
+        let mut status = Port::<u8>::new(0x64);
+        // wait until the controller's input buffer is empty
+        while status.read() & 0x02 != 0 {}
+        status.write(0xfe);
+    }
+    hlt_loop();
+}
@@ -2,9 +2,22 @@ use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
 use x86_64::structures::paging::{FrameAllocator, PhysFrame, Size4KiB};
 use x86_64::PhysAddr;
 
+/*
+ * Bad memory found at boot, and frames reserved for good, are remembered as
+ * [start, end) address ranges in a fixed array since there is no heap yet.
+ * Adjacent bad frames share a range. The last slot is kept for overflow: once
+ * the others are used up, a bad frame drops the rest of its memory region
+ * through it. Only one region can be dropped like that, further bad frames in
+ * other regions are reported and stay usable.
+ */
+const MAX_BAD_RANGES: usize = 64;
+const OVERFLOW: usize = MAX_BAD_RANGES - 1;
+const FRAME_SIZE: u64 = 4096;
+
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
+    bad_ranges: [Option<(u64, u64)>; MAX_BAD_RANGES],
 }
 
 impl BootInfoFrameAllocator {
@@ -17,6 +30,76 @@ impl BootInfoFrameAllocator {
         BootInfoFrameAllocator {
             memory_map,
             next: 0,
+            bad_ranges: [None; MAX_BAD_RANGES],
+        }
+    }
+
+    /// Never hand out `frame`, e.g. because it failed the boot-time memtest.
+    /// Returns false if there is no room left to remember it.
+    ///
+    /// Must be called before any frame is allocated, otherwise the indices
+    /// of the already handed out frames would shift.
+    pub fn mark_unusable(&mut self, frame: PhysFrame) -> bool {
+        assert!(self.next == 0, "frames already allocated");
+        let start = frame.start_address().as_u64();
+        let end = start + FRAME_SIZE;
+        for range in self.bad_ranges.iter_mut().flatten() {
+            if range.0 <= start && end <= range.1 {
+                return true;
+            }
+            // grow a range the frame is adjacent to
+            if range.1 == start {
+                range.1 = end;
+                return true;
+            }
+            if range.0 == end {
+                range.0 = start;
+                return true;
+            }
+        }
+        match self.bad_ranges[..OVERFLOW].iter_mut().find(|r| r.is_none()) {
+            Some(slot) => {
+                *slot = Some((start, end));
+                true
+            }
+            None => {
+                let (region_start, region_end) = self
+                    .memory_map
+                    .iter()
+                    .find(|r| r.range.start_addr() <= start && start < r.range.end_addr())
+                    .map_or((start, end), |r| (r.range.start_addr(), r.range.end_addr()));
+                let overflow = &mut self.bad_ranges[OVERFLOW];
+                let dropped = match *overflow {
+                    None => (start, region_end),
+                    // never grow it beyond the region it already covers
+                    Some((old_start, old_end)) if region_start <= old_start && old_end <= region_end => {
+                        (old_start.min(start), region_end)
+                    }
+                    Some(_) => {
+                        serial_println!("allocator: too many bad ranges, cannot mark {:#x} as bad", start);
+                        return false;
+                    }
+                };
+                serial_println!("allocator: too many bad ranges, dropping {:#x}-{:#x}", dropped.0, dropped.1);
+                *overflow = Some(dropped);
+                true
+            }
+        }
+    }
+
+    /// Take the first usable frame below `limit` for good, e.g. for code that
+    /// has to run in real mode. Like `mark_unusable`, must be called before any
+    /// frame is allocated.
+    pub fn reserve_below(&mut self, limit: u64) -> Option<PhysFrame> {
+        let frame = self.usable_frames().find(|frame| {
+            let addr = frame.start_address().as_u64();
+            // frame 0 holds the real mode IVT and BIOS data
+            addr != 0 && addr + FRAME_SIZE <= limit
+        })?;
+        if self.mark_unusable(frame) {
+            Some(frame)
+        } else {
+            None
         }
     }
 
@@ -31,7 +114,13 @@ impl BootInfoFrameAllocator {
         // transform to an iterator of frame start addresses
         let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
         // create `PhysFrame` types from the start addresses
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+        let frames = frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)));
+        // skip the frames marked as bad
+        let bad_ranges = self.bad_ranges;
+        frames.filter(move |frame| {
+            let addr = frame.start_address().as_u64();
+            !bad_ranges.iter().flatten().any(|&(start, end)| start <= addr && addr < end)
+        })
     }
 }
 
@@ -43,3 +132,37 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
         frame
     }
 }
+
+/// Print the memory map passed by the bootloader: every region with its type,
+/// followed by the total size of each region type.
+#[cfg_attr(not(feature = "dump_memory"), allow(dead_code))]
+pub fn dump_memory_map(memory_map: &MemoryMap) {
+    // (type, total bytes) pairs; the bootloader only uses a handful of types
+    let mut totals: [Option<(MemoryRegionType, u64)>; 16] = [None; 16];
+
+    serial_println!("memory map:");
+    for region in memory_map.iter() {
+        let start = region.range.start_addr();
+        let end = region.range.end_addr();
+        serial_println!(
+            "  [{:#012x} - {:#012x}) {:>8} KiB  {:?}",
+            start,
+            end,
+            (end - start) / 1024,
+            region.region_type
+        );
+
+        let slot = totals
+            .iter_mut()
+            .find(|t| t.map_or(true, |(ty, _)| ty == region.region_type));
+        if let Some(slot) = slot {
+            let size = slot.map_or(0, |(_, size)| size);
+            *slot = Some((region.region_type, size + end - start));
+        }
+    }
+
+    serial_println!("totals:");
+    for (ty, size) in totals.iter().filter_map(|t| *t) {
+        serial_println!("  {:>8} KiB  {:?}", size / 1024, ty);
+    }
+}
\ No newline at end of file
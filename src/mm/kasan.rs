@@ -0,0 +1,281 @@
+/*
+ * Lightweight kernel address sanitizer for the kernel heap.
+ *
+ * Every 8 bytes of the heap starting at HEAP_START are described by one shadow
+ * byte:
+ *     0            all 8 bytes are accessible
+ *     1..=7        only the first n bytes are accessible
+ *     REDZONE      red zone behind a heap object
+ *     FREED        the object has been freed
+ *     UNALLOCATED  never handed out by the allocator (or allocator metadata)
+ *
+ * The global allocator pads every object with a red zone, and freed objects
+ * stay in a quarantine for a while so that use-after-free is not hidden by
+ * the memory being reused at once. Accesses are checked by `check`, which the
+ * allocator also runs over every object it frees. The crate is not built with -Zsanitizer=kernel-address,
+ * because the allocators themselves keep their metadata inside the heap.
+ */
+use super::heap_allocator::{HEAP_SIZE, HEAP_START};
+use super::magazine::MagazineAllocator;
+use super::round_up;
+use crate::backtrace;
+use alloc::alloc::Layout;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+const SHADOW_SCALE: usize = 8;
+const REDZONE_SIZE: usize = 16;
+const QUARANTINE_SIZE: usize = 64;
+const MAX_RECORDS: usize = 512;
+const SITE_FRAMES: usize = 4;
+
+const REDZONE: u8 = 0xfa;
+const FREED: u8 = 0xfb;
+const UNALLOCATED: u8 = 0xfc;
+
+static mut SHADOW: [u8; HEAP_SIZE / SHADOW_SCALE] = [0; HEAP_SIZE / SHADOW_SCALE];
+
+/// Where an object was allocated and freed
+#[derive(Clone, Copy)]
+struct Record {
+    // allocation order, the ring does not keep it once it wrapped
+    seq: u64,
+    start: usize,
+    size: usize,
+    alloc_site: [u64; SITE_FRAMES],
+    free_site: [u64; SITE_FRAMES],
+    freed: bool,
+}
+
+const EMPTY_RECORD: Option<Record> = None;
+
+struct Records {
+    records: [Option<Record>; MAX_RECORDS],
+    // the oldest record is overwritten when the table is full
+    next: usize,
+    next_seq: u64,
+}
+
+struct Quarantine {
+    entries: [Option<(usize, Layout)>; QUARANTINE_SIZE],
+    next: usize,
+}
+
+static RECORDS: Mutex<Records> = Mutex::new(Records {
+    records: [EMPTY_RECORD; MAX_RECORDS],
+    next: 0,
+    next_seq: 0,
+});
+
+static QUARANTINE: Mutex<Quarantine> = Mutex::new(Quarantine {
+    entries: [None; QUARANTINE_SIZE],
+    next: 0,
+});
+
+/// Mark the whole heap as unallocated. Called once the heap is mapped.
+pub fn init() {
+    unsafe {
+        for byte in SHADOW.iter_mut() {
+            *byte = UNALLOCATED;
+        }
+    }
+    serial_println!("kasan: {} KiB shadow for the kernel heap", HEAP_SIZE / SHADOW_SCALE / 1024);
+}
+
+fn in_heap(addr: usize) -> bool {
+    addr >= HEAP_START && addr < HEAP_START + HEAP_SIZE
+}
+
+unsafe fn shadow(addr: usize) -> *mut u8 {
+    &mut SHADOW[(addr - HEAP_START) / SHADOW_SCALE] as *mut u8
+}
+
+/// Set the shadow of the 8-byte aligned range [start, start + size) to `value`
+unsafe fn poison(start: usize, size: usize, value: u8) {
+    let mut addr = start;
+    while addr < start + size {
+        *shadow(addr) = value;
+        addr += SHADOW_SCALE;
+    }
+}
+
+/// Make [start, start + size) accessible; `start` must be 8-byte aligned
+unsafe fn unpoison(start: usize, size: usize) {
+    poison(start, round_up(size, SHADOW_SCALE), 0);
+    if size % SHADOW_SCALE != 0 {
+        *shadow(start + size) = (size % SHADOW_SCALE) as u8;
+    }
+}
+
+/// Check that `size` bytes at `addr` can be accessed, report the access otherwise
+#[track_caller]
+pub fn check(addr: usize, size: usize, write: bool) {
+    if size == 0 || !in_heap(addr) || !in_heap(addr + size - 1) {
+        return;
+    }
+    for byte in addr..addr + size {
+        let value = unsafe { *shadow(byte) };
+        let offset = (byte % SHADOW_SCALE) as u8;
+        if value != 0 && (value >= SHADOW_SCALE as u8 || offset >= value) {
+            report(addr, size, write, byte, value);
+        }
+    }
+}
+
+#[track_caller]
+fn report(addr: usize, size: usize, write: bool, bad_addr: usize, value: u8) -> ! {
+    let kind = match value {
+        REDZONE => "heap-buffer-overflow",
+        FREED => "use-after-free",
+        UNALLOCATED => "wild-access",
+        _ => "heap-buffer-overflow",
+    };
+    serial_println!("==================================================");
+    serial_println!("kasan: {} on address {:#x}", kind, bad_addr);
+    serial_println!(
+        "{} of size {} at {:#x} from {}",
+        if write { "write" } else { "read" },
+        size,
+        addr,
+        core::panic::Location::caller()
+    );
+    backtrace::print_current();
+    print_record(bad_addr);
+    panic!("kasan: {} on address {:#x}", kind, bad_addr);
+}
+
+/// Print the allocation and free sites of the latest object at `addr`
+fn print_record(addr: usize) {
+    // an address in a red zone belongs to the object right in front of it
+    let record = interrupts::without_interrupts(|| {
+        RECORDS
+            .lock()
+            .records
+            .iter()
+            .filter_map(|r| *r)
+            .filter(|r| r.start <= addr && addr < r.start + r.size + REDZONE_SIZE + SHADOW_SCALE)
+            .max_by_key(|r| r.seq)
+    });
+    match record {
+        Some(record) => {
+            serial_println!("object [{:#x}, {:#x}) allocated at:", record.start, record.start + record.size);
+            backtrace::print(&record.alloc_site);
+            if record.freed {
+                serial_println!("freed at:");
+                backtrace::print(&record.free_site);
+            }
+        }
+        None => serial_println!("no allocation record for {:#x}", addr),
+    }
+}
+
+/// Layout of the object plus its red zone
+fn padded_layout(layout: Layout) -> Layout {
+    let size = round_up(layout.size(), SHADOW_SCALE) + REDZONE_SIZE;
+    Layout::from_size_align(size, layout.align().max(SHADOW_SCALE)).unwrap()
+}
+
+fn call_site() -> [u64; SITE_FRAMES] {
+    let mut frames = [0; SITE_FRAMES];
+    // skip this function, the kasan hook, GlobalAlloc and __rust_alloc/__rg_alloc
+    backtrace::capture(4, &mut frames);
+    frames
+}
+
+pub unsafe fn alloc(allocator: &MagazineAllocator, layout: Layout) -> *mut u8 {
+    let padded = padded_layout(layout);
+    let ptr = allocator.alloc_raw(padded);
+    if ptr.is_null() || !in_heap(ptr as usize) {
+        return ptr;
+    }
+    let start = ptr as usize;
+    poison(start, padded.size(), REDZONE);
+    unpoison(start, layout.size());
+
+    let mut record = Record {
+        seq: 0,
+        start,
+        size: layout.size(),
+        alloc_site: call_site(),
+        free_site: [0; SITE_FRAMES],
+        freed: false,
+    };
+    interrupts::without_interrupts(|| {
+        let mut records = RECORDS.lock();
+        let index = records.next;
+        record.seq = records.next_seq;
+        records.next_seq += 1;
+        records.records[index] = Some(record);
+        records.next = (index + 1) % MAX_RECORDS;
+    });
+    ptr
+}
+
+pub unsafe fn dealloc(allocator: &MagazineAllocator, ptr: *mut u8, layout: Layout) {
+    let padded = padded_layout(layout);
+    let start = ptr as usize;
+    if !in_heap(start) {
+        return allocator.dealloc_raw(ptr, padded);
+    }
+    match *shadow(start) {
+        FREED => report_bad_free("double-free", start),
+        REDZONE | UNALLOCATED => report_bad_free("invalid-free", start),
+        _ => {}
+    }
+    // freeing with a larger layout than allocated runs into the red zone
+    check(start, layout.size(), true);
+    poison(start, padded.size(), FREED);
+
+    let free_site = call_site();
+    interrupts::without_interrupts(|| {
+        let mut records = RECORDS.lock();
+        let record = records
+            .records
+            .iter_mut()
+            .filter_map(|r| r.as_mut())
+            .filter(|r| r.start == start && !r.freed)
+            .max_by_key(|r| r.seq);
+        if let Some(record) = record {
+            record.freed = true;
+            record.free_site = free_site;
+        }
+    });
+
+    // keep the object in quarantine and really free the oldest one
+    let evicted = interrupts::without_interrupts(|| {
+        let mut quarantine = QUARANTINE.lock();
+        let index = quarantine.next;
+        quarantine.next = (index + 1) % QUARANTINE_SIZE;
+        quarantine.entries[index].replace((start, padded))
+    });
+    if let Some((old_start, old_layout)) = evicted {
+        // the memory may be handed out again, so it is no longer "freed"
+        poison(old_start, old_layout.size(), UNALLOCATED);
+        allocator.dealloc_raw(old_start as *mut u8, old_layout);
+    }
+}
+
+fn report_bad_free(kind: &str, addr: usize) -> ! {
+    serial_println!("==================================================");
+    serial_println!("kasan: {} of {:#x}", kind, addr);
+    backtrace::print_current();
+    print_record(addr);
+    panic!("kasan: {} of {:#x}", kind, addr);
+}
+
+#[test_case]
+fn kasan_tracks_heap_objects() {
+    use alloc::boxed::Box;
+    let value = Box::new([0u8; 20]);
+    let addr = value.as_ptr() as usize;
+    // inside the object
+    check(addr, 20, false);
+    unsafe {
+        assert_eq!(*shadow(addr + 16), 4);
+        assert_eq!(*shadow(addr + 24), REDZONE);
+    }
+    drop(value);
+    unsafe {
+        assert_eq!(*shadow(addr), FREED);
+    }
+}
@@ -0,0 +1,155 @@
+/*
+ * Per-CPU magazine caches in front of the slab allocator.
+ *
+ * Each CPU owns one magazine per slab size class: a small LIFO stack of free
+ * objects. alloc and dealloc only touch the magazine of the current CPU with
+ * interrupts disabled, so the fast path takes no lock at all. When a magazine
+ * runs empty (or full), half a magazine is refilled from (or flushed to) the
+ * shared slabs under the slab lock in a single batch.
+ *
+ * Disabling interrupts is what separates the normal and the interrupt context
+ * on a CPU, the caches of other CPUs are never touched.
+ */
+use super::slab_allocator::{self, SlabAllocator, MAX_SLAB_ORDER, MIN_SLAB_ORDER};
+use super::Locked;
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+use core::ptr;
+use crate::smp::percpu::PerCpu;
+use x86_64::instructions::interrupts;
+
+const MAGAZINE_SIZE: usize = 32;
+const BATCH_SIZE: usize = MAGAZINE_SIZE / 2;
+const SIZE_CLASSES: usize = MAX_SLAB_ORDER - MIN_SLAB_ORDER;
+
+struct Magazine {
+    rounds: [*mut u8; MAGAZINE_SIZE],
+    count: usize,
+}
+
+struct CpuCache {
+    magazines: [Magazine; SIZE_CLASSES],
+}
+
+pub struct MagazineAllocator {
+    caches: PerCpu<UnsafeCell<CpuCache>>,
+    slab: Locked<SlabAllocator>,
+}
+
+// Each CpuCache is only accessed by its own CPU with interrupts disabled, so
+// no two threads ever use one at the same time
+unsafe impl Sync for MagazineAllocator {}
+
+const EMPTY_MAGAZINE: Magazine = Magazine {
+    rounds: [ptr::null_mut(); MAGAZINE_SIZE],
+    count: 0,
+};
+
+const EMPTY_CPU_CACHE: UnsafeCell<CpuCache> = UnsafeCell::new(CpuCache {
+    magazines: [EMPTY_MAGAZINE; SIZE_CLASSES],
+});
+
+/// The size class of `layout`, or None if it is too large for the slabs. The
+/// slab allocator picks the same order when the magazines pass it through.
+fn layout_to_order(layout: Layout) -> Option<u32> {
+    let order = slab_allocator::layout_to_order(layout);
+    if order >= MAX_SLAB_ORDER as u32 {
+        None
+    } else {
+        Some(order)
+    }
+}
+
+impl MagazineAllocator {
+    pub const fn new() -> Self {
+        MagazineAllocator {
+            caches: PerCpu::new([EMPTY_CPU_CACHE; crate::drivers::apic::MAX_CPUS]),
+            slab: Locked::new(SlabAllocator::new()),
+        }
+    }
+
+    pub unsafe fn init(&self, heap_start: usize, heap_size: usize) {
+        self.slab.lock().init(heap_start, heap_size);
+    }
+
+    /// The shared slab allocator behind the magazines
+    pub fn slab(&self) -> &Locked<SlabAllocator> {
+        &self.slab
+    }
+
+    /// Must be called with interrupts disabled
+    unsafe fn magazine(&self, order: u32) -> &mut Magazine {
+        let cache = &mut *self.caches.get().get();
+        &mut cache.magazines[order as usize - MIN_SLAB_ORDER]
+    }
+
+    /// Fill half of an empty magazine from the slabs
+    unsafe fn refill(&self, magazine: &mut Magazine, order: u32) {
+        let mut slab = self.slab.lock();
+        while magazine.count < BATCH_SIZE {
+            magazine.rounds[magazine.count] = slab.alloc_object(order);
+            magazine.count += 1;
+        }
+    }
+
+    /// Give half of a full magazine back to the slabs
+    unsafe fn flush(&self, magazine: &mut Magazine) {
+        let mut slab = self.slab.lock();
+        while magazine.count > MAGAZINE_SIZE - BATCH_SIZE {
+            magazine.count -= 1;
+            slab.free_object(magazine.rounds[magazine.count]);
+        }
+    }
+
+    pub(super) unsafe fn alloc_raw(&self, layout: Layout) -> *mut u8 {
+        let order = match layout_to_order(layout) {
+            Some(order) => order,
+            None => return self.slab.alloc(layout),
+        };
+        interrupts::without_interrupts(|| {
+            let magazine = self.magazine(order);
+            if magazine.count == 0 {
+                self.refill(magazine, order);
+            }
+            magazine.count -= 1;
+            magazine.rounds[magazine.count]
+        })
+    }
+
+    pub(super) unsafe fn dealloc_raw(&self, ptr: *mut u8, layout: Layout) {
+        let order = match layout_to_order(layout) {
+            Some(order) => order,
+            None => return self.slab.dealloc(ptr, layout),
+        };
+        interrupts::without_interrupts(|| {
+            let magazine = self.magazine(order);
+            if magazine.count == MAGAZINE_SIZE {
+                self.flush(magazine);
+            }
+            magazine.rounds[magazine.count] = ptr;
+            magazine.count += 1;
+        })
+    }
+}
+
+unsafe impl GlobalAlloc for MagazineAllocator {
+    #[cfg(not(feature = "kasan"))]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        self.alloc_raw(layout)
+    }
+
+    #[cfg(not(feature = "kasan"))]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.dealloc_raw(ptr, layout)
+    }
+
+    #[cfg(feature = "kasan")]
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        super::kasan::alloc(self, layout)
+    }
+
+    #[cfg(feature = "kasan")]
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        super::kasan::dealloc(self, ptr, layout)
+    }
+}
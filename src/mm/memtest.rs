@@ -0,0 +1,127 @@
+/*
+ * Boot-time physical memory tester.
+ *
+ * Every `Usable` region of the bootloader memory map is accessed through the
+ * physical memory offset mapping and checked with several patterns:
+ *   1. walking ones: a single set bit walks through each 64-bit word
+ *   2. address in address: each word holds its own physical address
+ *   3. inversions: a pattern and its complement are written in turn
+ * Frames that fail any pass are handed to the frame allocator as unusable.
+ *
+ * The tester must run before the frame allocator hands out any frame, because
+ * it overwrites the whole content of every usable frame.
+ */
+use super::allocator::BootInfoFrameAllocator;
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::ptr;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::{PhysAddr, VirtAddr};
+
+const FRAME_SIZE: u64 = 4096;
+const WORDS_PER_FRAME: usize = FRAME_SIZE as usize / 8;
+const INVERSION_PATTERNS: [u64; 3] = [
+    0x5555_5555_5555_5555,
+    0x3333_3333_3333_3333,
+    0x0f0f_0f0f_0f0f_0f0f,
+];
+
+#[derive(Debug, Default)]
+pub struct MemtestSummary {
+    pub regions: usize,
+    pub tested_frames: u64,
+    pub bad_frames: u64,
+}
+
+/// Test every usable frame and mark the bad ones in `frame_allocator`.
+///
+/// This function is unsafe because the caller must guarantee that the complete
+/// physical memory is mapped at `physical_memory_offset` and that no usable
+/// frame is in use yet.
+pub unsafe fn run(
+    memory_map: &MemoryMap,
+    physical_memory_offset: VirtAddr,
+    frame_allocator: &mut BootInfoFrameAllocator,
+) -> MemtestSummary {
+    let mut summary = MemtestSummary::default();
+    serial_println!("memtest: testing usable memory");
+
+    for region in memory_map
+        .iter()
+        .filter(|r| r.region_type == MemoryRegionType::Usable)
+    {
+        summary.regions += 1;
+        let mut addr = region.range.start_addr();
+        while addr < region.range.end_addr() {
+            let words = (physical_memory_offset + addr).as_mut_ptr::<u64>();
+            summary.tested_frames += 1;
+            if !test_frame(words, addr) {
+                summary.bad_frames += 1;
+                serial_println!("memtest: bad frame at {:#x}", addr);
+                frame_allocator.mark_unusable(PhysFrame::containing_address(PhysAddr::new(addr)));
+            }
+            addr += FRAME_SIZE;
+        }
+    }
+
+    serial_println!(
+        "memtest: {} regions, {} frames ({} KiB) tested, {} bad",
+        summary.regions,
+        summary.tested_frames,
+        summary.tested_frames * FRAME_SIZE / 1024,
+        summary.bad_frames
+    );
+    summary
+}
+
+/// Run all patterns on one frame. `words` is the virtual address of the frame
+/// and `paddr` its physical address.
+unsafe fn test_frame(words: *mut u64, paddr: u64) -> bool {
+    walking_ones(words) && address_in_address(words, paddr) && inversions(words)
+}
+
+unsafe fn walking_ones(words: *mut u64) -> bool {
+    for bit in 0..64 {
+        let pattern = 1u64 << bit;
+        for i in 0..WORDS_PER_FRAME {
+            ptr::write_volatile(words.add(i), pattern);
+        }
+        for i in 0..WORDS_PER_FRAME {
+            if ptr::read_volatile(words.add(i)) != pattern {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+unsafe fn address_in_address(words: *mut u64, paddr: u64) -> bool {
+    for i in 0..WORDS_PER_FRAME {
+        ptr::write_volatile(words.add(i), paddr + (i * 8) as u64);
+    }
+    for i in 0..WORDS_PER_FRAME {
+        if ptr::read_volatile(words.add(i)) != paddr + (i * 8) as u64 {
+            return false;
+        }
+    }
+    true
+}
+
+unsafe fn inversions(words: *mut u64) -> bool {
+    for &pattern in INVERSION_PATTERNS.iter() {
+        for &value in [pattern, !pattern].iter() {
+            for i in 0..WORDS_PER_FRAME {
+                ptr::write_volatile(words.add(i), value);
+            }
+            for i in 0..WORDS_PER_FRAME {
+                if ptr::read_volatile(words.add(i)) != value {
+                    return false;
+                }
+            }
+        }
+    }
+    // leave the frame zeroed
+    for i in 0..WORDS_PER_FRAME {
+        ptr::write_volatile(words.add(i), 0);
+    }
+    true
+}
@@ -6,6 +6,14 @@ pub mod pool_allocator;
 pub mod segregated_alloctor;
 pub mod buddy_allocator;
 pub mod slab_allocator;
+pub mod magazine;
+#[cfg(feature = "kasan")]
+pub mod kasan;
+#[cfg(feature = "memtest")]
+pub mod memtest;
+
+use alloc::alloc::{AllocError, Allocator, GlobalAlloc, Layout};
+use core::ptr::{self, NonNull};
 
 fn round_up(addr: usize, align: usize) -> usize {
     let remainder = addr % align;
@@ -44,4 +52,56 @@ impl<A> Locked<A> {
     pub fn lock(&self) -> spin::MutexGuard<A> {
         self.inner.lock()
     }
-}
\ No newline at end of file
+}
+
+/// Allocator::allocate on top of a GlobalAlloc
+fn allocate_global(allocator: &impl GlobalAlloc, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+    // zero-sized requests never touch the underlying allocator
+    if layout.size() == 0 {
+        let dangling = layout.align() as *mut u8;
+        return NonNull::new(ptr::slice_from_raw_parts_mut(dangling, 0)).ok_or(AllocError);
+    }
+    let ptr = unsafe { allocator.alloc(layout) };
+    NonNull::new(ptr::slice_from_raw_parts_mut(ptr, layout.size())).ok_or(AllocError)
+}
+
+/// Allocator::deallocate on top of a GlobalAlloc
+unsafe fn deallocate_global(allocator: &impl GlobalAlloc, ptr: NonNull<u8>, layout: Layout) {
+    if layout.size() != 0 {
+        allocator.dealloc(ptr.as_ptr(), layout);
+    }
+}
+
+/*
+ * Every Locked<A> that works as a GlobalAlloc can also be used as an
+ * Allocator, so collections can be placed in a specific allocator:
+ *     Vec::with_capacity_in(n, &DMA_POOL)
+ *     Box::new_in(obj, &TASK_CACHE)
+ * `&Locked<A>` is an Allocator as well through the blanket impl in core.
+ */
+unsafe impl<A> Allocator for Locked<A>
+where
+    Locked<A>: GlobalAlloc,
+{
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        allocate_global(self, layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        deallocate_global(self, ptr, layout)
+    }
+}
+
+/*
+ * The kernel heap does its own locking, so it is an Allocator without the
+ * Locked wrapper: Vec::new_in(heap_allocator::allocator())
+ */
+unsafe impl Allocator for magazine::MagazineAllocator {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        allocate_global(self, layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        deallocate_global(self, ptr, layout)
+    }
+}
@@ -1,4 +1,9 @@
-use x86_64::structures::paging::{OffsetPageTable, PageTable};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::structures::paging::mapper::MapToError;
+use x86_64::structures::paging::{
+    FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame, Size4KiB,
+};
+use crate::smp::tlb::TlbBatch;
 use x86_64::{PhysAddr, VirtAddr};
 /// A 64-bit page table entry.
 // #[derive(Clone)]
@@ -31,6 +36,54 @@ unsafe fn active_level_4_page_table(physical_memory_offset: VirtAddr) -> &'stati
     &mut *page_table_ptr
 }
 
+/// Virtual window where device registers (MMIO) are mapped, one page each
+const MMIO_START: u64 = 0x5555_0000_0000;
+static MMIO_NEXT: AtomicU64 = AtomicU64::new(MMIO_START);
+
+/// Map the page containing the device registers at `paddr` as uncached memory
+/// and return the virtual address of `paddr`.
+///
+/// Device memory is usually not covered by the physical memory mapping of the
+/// bootloader, which only maps the regions of the memory map.
+pub fn map_mmio(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    paddr: PhysAddr,
+) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let frame = PhysFrame::<Size4KiB>::containing_address(paddr);
+    let va = VirtAddr::new(MMIO_NEXT.fetch_add(4096, Ordering::Relaxed));
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_EXECUTE;
+    unsafe {
+        mapper
+            .map_to(Page::containing_address(va), frame, flags, frame_allocator)?
+            .flush();
+    }
+    Ok(va + (paddr.as_u64() - frame.start_address().as_u64()))
+}
+
+/// Unmap `count` pages starting at `start` and invalidate them on all CPUs
+/// with a single shootdown. Pages that were not mapped are skipped. Return
+/// the number of pages unmapped; freeing their frames is up to the caller.
+#[allow(dead_code)]
+pub fn unmap_pages(mapper: &mut impl Mapper<Size4KiB>, start: Page<Size4KiB>, count: u64) -> usize {
+    let mut batch = TlbBatch::new();
+    let mut unmapped = 0;
+    for page in Page::range(start, start + count) {
+        if let Ok((_frame, flush)) = mapper.unmap(page) {
+            // the batch below flushes on every CPU
+            flush.ignore();
+            batch.add(page.start_address());
+            unmapped += 1;
+        }
+    }
+    batch.flush();
+    unmapped
+}
+
 /// Return a virtual address of a given physical address used by kernel
 #[allow(dead_code)]
 pub fn phys_to_virt(paddr: PhysAddr, physical_memory_offset: VirtAddr) -> VirtAddr {
@@ -65,9 +118,7 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Opt
 
     // traverse the multi-level page table
     for &index in &table_index {
-        let vaddr = physical_memory_offset + table_frame.start_address().as_u64();
-        let page_table_ptr: *const PageTable = vaddr.as_ptr();
-        let page_table: &PageTable = unsafe { &*page_table_ptr };
+        let page_table = unsafe { page_table_at(table_frame, physical_memory_offset) };
 
         let pte = &page_table[index];
         table_frame = match pte.frame() {
@@ -81,3 +132,134 @@ fn translate_addr_inner(addr: VirtAddr, physical_memory_offset: VirtAddr) -> Opt
         table_frame.start_address().as_u64() + u64::from(addr.page_offset()),
     ))
 }
+
+/// Return the page table stored in `frame` through the physical memory mapping
+unsafe fn page_table_at(frame: PhysFrame, physical_memory_offset: VirtAddr) -> &'static PageTable {
+    let vaddr = physical_memory_offset + frame.start_address().as_u64();
+    let page_table_ptr: *const PageTable = vaddr.as_ptr();
+    &*page_table_ptr
+}
+
+/// A run of virtually and physically contiguous pages with the same flags
+struct MappedRange {
+    virt_start: u64,
+    virt_end: u64,
+    phys_start: u64,
+    flags: PageTableFlags,
+}
+
+impl MappedRange {
+    fn print(&self) {
+        let flag = |f: PageTableFlags, c: char| if self.flags.contains(f) { c } else { '-' };
+        serial_println!(
+            "  {:#018x}-{:#018x} -> {:#014x} {:>10} KiB {}{}{}{}",
+            self.virt_start,
+            self.virt_end,
+            self.phys_start,
+            self.virt_end.wrapping_sub(self.virt_start) / 1024,
+            flag(PageTableFlags::WRITABLE, 'W'),
+            flag(PageTableFlags::USER_ACCESSIBLE, 'U'),
+            if self.flags.contains(PageTableFlags::NO_EXECUTE) { '-' } else { 'x' },
+            flag(PageTableFlags::HUGE_PAGE, 'H'),
+        );
+    }
+}
+
+/// Flags that are checked when coalescing ranges
+fn interesting_flags(flags: PageTableFlags) -> PageTableFlags {
+    flags
+        & (PageTableFlags::WRITABLE
+            | PageTableFlags::USER_ACCESSIBLE
+            | PageTableFlags::NO_EXECUTE
+            | PageTableFlags::HUGE_PAGE)
+}
+
+/// Sign extend a 48-bit virtual address to its canonical form
+fn canonical(addr: u64) -> u64 {
+    ((addr << 16) as i64 >> 16) as u64
+}
+
+/// Dump every mapped range of the active level 4 page table.
+///
+/// Contiguous mappings with the same flags are coalesced. Flags are printed as
+/// W (writable), U (user accessible), x (executable) and H (huge page). They
+/// are the effective permissions of the whole walk, not only of the leaf.
+///
+/// This function is unsafe because the caller must guarantee that the
+/// complete physical memory is mapped to virtual memory at the passed
+/// `physical_memory_offset`.
+#[cfg_attr(not(feature = "dump_memory"), allow(dead_code))]
+pub unsafe fn dump_address_space(physical_memory_offset: VirtAddr) {
+    use x86_64::registers::control::Cr3;
+
+    let (level4_frame, _) = Cr3::read();
+    let mut current: Option<MappedRange> = None;
+
+    serial_println!("address space (cr3 = {:#x}):", level4_frame.start_address().as_u64());
+    let all_allowed = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    walk_table(level4_frame, 4, 0, all_allowed, physical_memory_offset, &mut current);
+    if let Some(range) = current {
+        range.print();
+    }
+}
+
+/// Permissions of an entry given the effective permissions of its parent:
+/// writable and user accessible only if every level allows it, no execute if
+/// any level sets it.
+fn effective_flags(parent: PageTableFlags, entry: PageTableFlags) -> PageTableFlags {
+    let permissive = PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+    (entry & (parent | !permissive)) | (parent & PageTableFlags::NO_EXECUTE)
+}
+
+/// Walk the page table in `frame` which is a level `level` table mapping the
+/// addresses starting at `base`. `parent_flags` are the effective permissions
+/// of the entries above.
+unsafe fn walk_table(
+    frame: PhysFrame,
+    level: u32,
+    base: u64,
+    parent_flags: PageTableFlags,
+    physical_memory_offset: VirtAddr,
+    current: &mut Option<MappedRange>,
+) {
+    let table = page_table_at(frame, physical_memory_offset);
+    // each entry at level 1 maps 4 KiB, each level above maps 512 times more
+    let entry_size = 1u64 << (12 + 9 * (level - 1));
+
+    for (index, entry) in table.iter().enumerate() {
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        let virt = canonical(base + index as u64 * entry_size);
+        let is_leaf = level == 1 || entry.flags().contains(PageTableFlags::HUGE_PAGE);
+        let entry_flags = effective_flags(parent_flags, entry.flags());
+        if !is_leaf {
+            let next = PhysFrame::containing_address(entry.addr());
+            walk_table(next, level - 1, virt, entry_flags, physical_memory_offset, current);
+            continue;
+        }
+
+        let phys = entry.addr().as_u64();
+        let flags = interesting_flags(entry_flags);
+        match current {
+            Some(range)
+                if range.virt_end == virt
+                    && range.phys_start + range.virt_end.wrapping_sub(range.virt_start) == phys
+                    && range.flags == flags =>
+            {
+                range.virt_end = range.virt_end.wrapping_add(entry_size);
+            }
+            _ => {
+                if let Some(range) = current.take() {
+                    range.print();
+                }
+                *current = Some(MappedRange {
+                    virt_start: virt,
+                    virt_end: virt.wrapping_add(entry_size),
+                    phys_start: phys,
+                    flags,
+                });
+            }
+        }
+    }
+}
\ No newline at end of file
@@ -2,9 +2,10 @@ use super::buddy_allocator::{BuddyAllocator, PAGE_SIZE};
 use super::{round_down, Locked};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr;
+use x86_64::instructions::interrupts;
 
-const MAX_SLAB_ORDER: usize = 12;
-const MIN_SLAB_ORDER: usize = 5;
+pub(super) const MAX_SLAB_ORDER: usize = 12;
+pub(super) const MIN_SLAB_ORDER: usize = 5;
 const DEFAULT_SLAB_SIZE: usize = 8 * 1024;
 
 #[repr(C)]
@@ -60,6 +61,13 @@ pub fn size_to_order(size: usize) -> u32 {
     order as u32
 }
 
+/// The slab order serving `layout`. Slots are aligned to their size, so the
+/// alignment selects the order as well; orders from MAX_SLAB_ORDER on go to
+/// the buddy system.
+pub fn layout_to_order(layout: Layout) -> u32 {
+    size_to_order(layout.size().max(layout.align()))
+}
+
 impl SlabAllocator {
     pub const fn new() -> Self {
         SlabAllocator {
@@ -146,28 +154,17 @@ impl SlabAllocator {
             }
         }
     }
-}
 
-unsafe impl GlobalAlloc for Locked<SlabAllocator> {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let order = size_to_order(layout.size());
-        if order >= MAX_SLAB_ORDER as u32 {
-            let allocator = &mut self.lock().fallback_allocator;
-            match allocator.get_free_pages(layout.size(), layout.align()) {
-                Some(page) => allocator.page_to_virt(page) as *mut u8,
-                None => ptr::null_mut(),
-            }
-        } else {
-            let mut allocator = self.lock();
-            let free_slot = allocator.find_free_slot(order).unwrap();
-            return free_slot.start_addr() as *mut u8;
-        }
+    /// Take one free object of size `1 << order` from the slabs
+    pub(super) unsafe fn alloc_object(&mut self, order: u32) -> *mut u8 {
+        let free_slot = self.find_free_slot(order).unwrap();
+        free_slot.start_addr() as *mut u8
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let mut allocator = self.lock();
+    /// Give `ptr` back to its slab, or to the buddy system if it is not a slab object
+    pub(super) unsafe fn free_object(&mut self, ptr: *mut u8) {
         let page_addr = round_down(ptr as usize, PAGE_SIZE);
-        let page = allocator.fallback_allocator.virt_to_page(page_addr);
+        let page = self.fallback_allocator.virt_to_page(page_addr);
         match page.slab.as_mut() {
             Some(slab_header) => {
                 /* Free this slot in the corresponding slab */
@@ -181,8 +178,34 @@ unsafe impl GlobalAlloc for Locked<SlabAllocator> {
             }
             None => {
                 /* Free the page in buddy system */
-                allocator.fallback_allocator.free_pages(page);
+                self.fallback_allocator.free_pages(page);
             }
         }
     }
 }
+
+/*
+ * The magazines take the slab lock with interrupts disabled, so it must never
+ * be held with interrupts enabled: an interrupt, or the thread preempting the
+ * holder, would spin on it forever.
+ */
+unsafe impl GlobalAlloc for Locked<SlabAllocator> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let order = layout_to_order(layout);
+        interrupts::without_interrupts(|| {
+            if order >= MAX_SLAB_ORDER as u32 {
+                let allocator = &mut self.lock().fallback_allocator;
+                match allocator.get_free_pages(layout.size(), layout.align()) {
+                    Some(page) => allocator.page_to_virt(page) as *mut u8,
+                    None => ptr::null_mut(),
+                }
+            } else {
+                self.lock().alloc_object(order)
+            }
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
+        interrupts::without_interrupts(|| self.lock().free_object(ptr));
+    }
+}
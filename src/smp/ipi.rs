@@ -0,0 +1,162 @@
+/*
+ * Inter-processor interrupts.
+ *
+ * IPIs are fixed interrupts sent through the local APIC to the local APIC of
+ * another CPU. They use the vectors right below the APIC spurious vector:
+ *   RESCHEDULE     the target's scheduler has a more urgent thread to run
+ *   CALL_FUNCTION  the target has functions queued by `smp_call_function`
+ *   TLB_SHOOTDOWN  the target must invalidate TLB entries, see the tlb module
+ *
+ * A queued call is shared by all its targets and counts the CPUs that have
+ * not run it yet. A waiting caller keeps serving calls and shootdowns sent to
+ * itself, so two CPUs calling each other with interrupts disabled cannot
+ * deadlock.
+ */
+use super::{cpu_count, current_cpu, other_cpus, tlb};
+use crate::drivers::apic::LocalApic;
+use crate::interrupts::{bottom_half, stats};
+use crate::task::scheduler;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::mem;
+use core::sync::atomic::{spin_loop_hint, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+pub const RESCHEDULE_VECTOR: u8 = 0xfc;
+pub const CALL_FUNCTION_VECTOR: u8 = 0xfd;
+pub const TLB_SHOOTDOWN_VECTOR: u8 = 0xfe;
+
+struct Call {
+    func: Box<dyn Fn() + Send + Sync>,
+    // targets that did not run `func` yet
+    pending: AtomicUsize,
+}
+
+crate::percpu! { static CALL_QUEUE: Mutex<Vec<Arc<Call>>> = Mutex::new(Vec::new()); }
+
+/// Send `vector` to CPU `cpu`
+pub fn send(cpu: usize, vector: u8) {
+    if let (Some(lapic), Some(apic_id)) = (LocalApic::get(), super::apic_id(cpu)) {
+        lapic.send_ipi(apic_id, vector as u32);
+    }
+}
+
+/// Send a non-maskable interrupt to CPU `cpu`
+pub fn send_nmi(cpu: usize) {
+    if let (Some(lapic), Some(apic_id)) = (LocalApic::get(), super::apic_id(cpu)) {
+        lapic.send_nmi(apic_id);
+    }
+}
+
+/// Run the calls queued for this CPU
+fn run_calls() {
+    let calls = interrupts::without_interrupts(|| mem::replace(&mut *CALL_QUEUE.get().lock(), Vec::new()));
+    for call in calls {
+        (call.func)();
+        call.pending.fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// Serve requests to this CPU until `done` returns true
+pub(super) fn wait_until(mut done: impl FnMut() -> bool) {
+    while !done() {
+        run_calls();
+        tlb::handle_pending();
+        spin_loop_hint();
+    }
+}
+
+/// Queue `func` on `cpus` and kick them
+fn queue_call(cpus: impl Iterator<Item = usize> + Clone, func: impl Fn() + Send + Sync + 'static) -> Arc<Call> {
+    let call = Arc::new(Call {
+        func: Box::new(func),
+        pending: AtomicUsize::new(cpus.clone().count()),
+    });
+    for cpu in cpus {
+        interrupts::without_interrupts(|| CALL_QUEUE.on(cpu).lock().push(call.clone()));
+        send(cpu, CALL_FUNCTION_VECTOR);
+    }
+    call
+}
+
+fn wait_for_call(call: &Call) {
+    wait_until(|| call.pending.load(Ordering::Acquire) == 0);
+}
+
+/// Run `func` on all other CPUs. With `wait`, return only after all of them
+/// finished it.
+#[allow(dead_code)]
+pub fn smp_call_function(func: impl Fn() + Send + Sync + 'static, wait: bool) {
+    let call = queue_call(other_cpus(), func);
+    if wait {
+        wait_for_call(&call);
+    }
+}
+
+/// Run `func` on CPU `cpu`, which may be the current one
+#[allow(dead_code)]
+pub fn smp_call_function_single(cpu: usize, func: impl Fn() + Send + Sync + 'static, wait: bool) {
+    if cpu == current_cpu() {
+        interrupts::without_interrupts(func);
+    } else if cpu < cpu_count() {
+        let call = queue_call(core::iter::once(cpu), func);
+        if wait {
+            wait_for_call(&call);
+        }
+    }
+}
+
+/// Run `func` on every CPU, including this one
+pub fn on_each_cpu(func: impl Fn() + Send + Sync + 'static, wait: bool) {
+    let func = Arc::new(func);
+    let remote = func.clone();
+    let call = queue_call(other_cpus(), move || remote());
+    interrupts::without_interrupts(|| func());
+    if wait {
+        wait_for_call(&call);
+    }
+}
+
+fn end_of_interrupt() {
+    if let Some(lapic) = LocalApic::get() {
+        lapic.end_of_interrupt();
+    }
+}
+
+extern "x86-interrupt" fn reschedule_handler(_stack_frame: &mut InterruptStackFrame) {
+    stats::measure(RESCHEDULE_VECTOR, end_of_interrupt);
+    if !bottom_half::in_bottom_half() {
+        scheduler::preempt_if_needed();
+    }
+}
+
+extern "x86-interrupt" fn call_function_handler(_stack_frame: &mut InterruptStackFrame) {
+    stats::measure(CALL_FUNCTION_VECTOR, || {
+        run_calls();
+        end_of_interrupt();
+    });
+}
+
+extern "x86-interrupt" fn tlb_shootdown_handler(_stack_frame: &mut InterruptStackFrame) {
+    stats::measure(TLB_SHOOTDOWN_VECTOR, || {
+        tlb::handle_pending();
+        end_of_interrupt();
+    });
+}
+
+pub fn install_handlers(idt: &mut InterruptDescriptorTable) {
+    idt[RESCHEDULE_VECTOR as usize].set_handler_fn(reschedule_handler);
+    idt[CALL_FUNCTION_VECTOR as usize].set_handler_fn(call_function_handler);
+    idt[TLB_SHOOTDOWN_VECTOR as usize].set_handler_fn(tlb_shootdown_handler);
+}
+
+#[test_case]
+fn call_function_runs_everywhere() {
+    static RAN: AtomicUsize = AtomicUsize::new(0);
+    RAN.store(0, Ordering::SeqCst);
+    on_each_cpu(|| { RAN.fetch_add(1, Ordering::SeqCst); }, true);
+    assert_eq!(RAN.load(Ordering::SeqCst), cpu_count());
+}
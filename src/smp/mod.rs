@@ -0,0 +1,207 @@
+/*
+ * Symmetric multiprocessing.
+ *
+ * The bootstrap processor (BSP) starts the application processors (APs) the
+ * firmware reported, one after another, with the INIT-SIPI-SIPI sequence. Each
+ * AP runs the real-mode trampoline into long mode and then `ap_main` on its
+ * own stack, loads its own GDT and TSS, the shared IDT, enables its local APIC
+ * and settles in its idle loop. External interrupts stay routed to the BSP.
+ *
+ * Only the BSP runs threads. APs are parked: they have no scheduler, so
+ * `scheduler::try_current_id` is None there and spawning a thread on them
+ * panics. They halt and only wake up for IPIs, like TLB shootdowns, or to
+ * become the hard lockup watcher.
+ *
+ * CPUs are numbered 0..cpu_count() in the order they came up, the BSP is 0.
+ */
+pub mod ipi;
+pub mod percpu;
+pub mod tlb;
+mod trampoline;
+
+use crate::drivers::apic::{self, LocalApic, MAX_CPUS};
+use crate::mm::allocator::BootInfoFrameAllocator;
+use crate::time::clocksource;
+use alloc::vec;
+use core::sync::atomic::{spin_loop_hint, AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{FrameAllocator, Mapper, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+
+/// Stack of an AP's idle task
+const AP_STACK_SIZE: usize = 16 * 1024;
+
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(1);
+const NO_APIC: AtomicU8 = AtomicU8::new(0);
+/// Local APIC id of each CPU
+static APIC_IDS: [AtomicU8; MAX_CPUS] = [NO_APIC; MAX_CPUS];
+const OFFLINE: AtomicBool = AtomicBool::new(false);
+static ONLINE: [AtomicBool; MAX_CPUS] = [OFFLINE; MAX_CPUS];
+
+/// Number of CPUs up and running
+pub fn cpu_count() -> usize {
+    CPU_COUNT.load(Ordering::Acquire)
+}
+
+/// Number of the CPU this runs on
+pub fn current_cpu() -> usize {
+    percpu::cpu_id()
+}
+
+/// Local APIC id of CPU `cpu`
+pub fn apic_id(cpu: usize) -> Option<u8> {
+    if cpu < cpu_count() {
+        Some(APIC_IDS[cpu].load(Ordering::Relaxed))
+    } else {
+        None
+    }
+}
+
+/// All CPUs but the current one
+pub fn other_cpus() -> impl Iterator<Item = usize> + Clone {
+    let current = current_cpu();
+    (0..cpu_count()).filter(move |&cpu| cpu != current)
+}
+
+/// Reserve a frame below 1 MiB for the AP trampoline, the SIPI vector can
+/// only point there. Must be called before any frame is allocated.
+pub fn reserve_trampoline_frame(frame_allocator: &mut BootInfoFrameAllocator) -> Option<PhysFrame> {
+    frame_allocator.reserve_below(0x10_0000)
+}
+
+fn delay_us(us: u64) {
+    let deadline = clocksource::now() + us * 1000;
+    while clocksource::now() < deadline {
+        spin_loop_hint();
+    }
+}
+
+/// Boot all application processors. Needs the local APIC, the heap and the
+/// clocksource.
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    physical_memory_offset: VirtAddr,
+    trampoline_frame: Option<PhysFrame>,
+) {
+    let bsp = match LocalApic::get() {
+        Some(lapic) => lapic,
+        None => return,
+    };
+    let bsp_id = bsp.id();
+    APIC_IDS[0].store(bsp_id, Ordering::Relaxed);
+    ONLINE[0].store(true, Ordering::Release);
+
+    let frame = match trampoline_frame {
+        Some(frame) => frame,
+        None => {
+            serial_println!("smp: no page below 1 MiB for the trampoline, not starting APs");
+            return;
+        }
+    };
+    // the trampoline enables paging while running at its physical address
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    match unsafe { mapper.identity_map(frame, flags, frame_allocator) } {
+        Ok(flush) => flush.flush(),
+        Err(err) => {
+            serial_println!("smp: failed to identity map the trampoline: {:?}", err);
+            return;
+        }
+    }
+    let trampoline_virt = physical_memory_offset + frame.start_address().as_u64();
+    let cr3 = Cr3::read().0.start_address();
+    if cr3.as_u64() >= 1 << 32 {
+        serial_println!("smp: page tables at {:#x} out of reach for the trampoline, not starting APs", cr3.as_u64());
+        return;
+    }
+
+    for apic_id in apic::processors().iter().filter_map(|p| *p) {
+        if apic_id == bsp_id {
+            continue;
+        }
+        let cpu = CPU_COUNT.load(Ordering::Relaxed);
+        if cpu >= MAX_CPUS {
+            break;
+        }
+        // the idle task's stack, it lives as long as the CPU
+        let stack = alloc::boxed::Box::leak(vec![0u64; AP_STACK_SIZE / 8].into_boxed_slice());
+        let stack_top = stack.as_ptr() as u64 + AP_STACK_SIZE as u64;
+        APIC_IDS[cpu].store(apic_id, Ordering::Relaxed);
+        unsafe {
+            trampoline::install(
+                frame.start_address(),
+                trampoline_virt,
+                cr3,
+                apic_id,
+                cpu,
+                stack_top,
+                ap_main as u64,
+            );
+        }
+
+        let page = (frame.start_address().as_u64() >> 12) as u8;
+        bsp.send_init(apic_id);
+        delay_us(10_000);
+        bsp.send_startup(apic_id, page);
+        delay_us(200);
+        if !wait_online(cpu, 1000) {
+            // the second SIPI is only needed if the first one was missed
+            bsp.send_startup(apic_id, page);
+        }
+        let mut started = wait_online(cpu, 100_000);
+        if !started && !unsafe { trampoline::withdraw(trampoline_virt) } {
+            /*
+             * It took its parameters just as we gave up on it. It runs as
+             * `cpu` now, so its slot must not go to the next AP.
+             */
+            while !ONLINE[cpu].load(Ordering::Acquire) {
+                spin_loop_hint();
+            }
+            started = true;
+        }
+        if started {
+            CPU_COUNT.fetch_add(1, Ordering::AcqRel);
+        } else {
+            serial_println!("smp: CPU with APIC id {} did not start", apic_id);
+        }
+    }
+    serial_println!("smp: {} CPUs online", cpu_count());
+}
+
+fn wait_online(cpu: usize, timeout_us: u64) -> bool {
+    let deadline = clocksource::now() + timeout_us * 1000;
+    while clocksource::now() < deadline {
+        if ONLINE[cpu].load(Ordering::Acquire) {
+            return true;
+        }
+        spin_loop_hint();
+    }
+    ONLINE[cpu].load(Ordering::Acquire)
+}
+
+/// First Rust code of an application processor, called by the trampoline
+extern "C" fn ap_main(cpu: u64) -> ! {
+    percpu::init(cpu as usize);
+    crate::interrupts::ap_interrupt_init();
+    crate::task::fpu::init();
+    ONLINE[cpu as usize].store(true, Ordering::Release);
+    serial_println!("smp: CPU {} (APIC id {}) online", cpu, LocalApic::get().map_or(0, |l| l.id()));
+    idle_loop()
+}
+
+/// Where an application processor stays parked. There is no scheduler
+/// behind it, the loop only serves interrupts.
+fn idle_loop() -> ! {
+    loop {
+        if crate::watchdog::is_watcher(current_cpu()) {
+            crate::watchdog::hard_lockup_watcher();
+        }
+        x86_64::instructions::interrupts::enable_and_hlt();
+    }
+}
+
+#[test_case]
+fn current_cpu_is_bsp() {
+    assert_eq!(current_cpu(), 0);
+    assert!(cpu_count() >= 1);
+}
@@ -0,0 +1,140 @@
+/*
+ * Per-CPU data.
+ *
+ * IA32_GS_BASE of every CPU points to its `CpuArea`, so the area of the
+ * current CPU is one gs-relative load away, without locks and without asking
+ * the local APIC. The area holds the hottest fields directly: its own address,
+ * the CPU number, the running thread, the interrupt nesting depth and the
+ * preemption count. Larger per-CPU variables are declared with `percpu!`,
+ * which keeps one instance per CPU and picks the current one by CPU number.
+ *
+ * There is no user mode yet, so IA32_KERNEL_GS_BASE is set to the same area.
+ * Entry code coming from user mode will have to `swapgs` to reach it.
+ */
+use crate::drivers::apic::MAX_CPUS;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::model_specific::Msr;
+
+const IA32_GS_BASE: u32 = 0xc000_0101;
+const IA32_KERNEL_GS_BASE: u32 = 0xc000_0102;
+
+/// `current_thread` while the CPU runs no thread
+pub const NO_THREAD: u64 = u64::MAX;
+
+/// Layout is fixed, the fields are read with gs-relative loads
+#[repr(C)]
+pub struct CpuArea {
+    self_ptr: AtomicU64,    // gs:[0]
+    cpu_id: AtomicU64,      // gs:[8]
+    pub current_thread: AtomicU64,
+    pub irq_depth: AtomicU64,
+    pub preempt_count: AtomicU64,
+}
+
+const EMPTY_AREA: CpuArea = CpuArea {
+    self_ptr: AtomicU64::new(0),
+    cpu_id: AtomicU64::new(0),
+    current_thread: AtomicU64::new(NO_THREAD),
+    irq_depth: AtomicU64::new(0),
+    preempt_count: AtomicU64::new(0),
+};
+
+static AREAS: [CpuArea; MAX_CPUS] = [EMPTY_AREA; MAX_CPUS];
+
+/// Point the GS base of the calling CPU to the area of `cpu`. Must be the
+/// first thing a CPU does, before anything uses per-CPU data.
+pub fn init(cpu: usize) {
+    let area = &AREAS[cpu];
+    let address = area as *const CpuArea as u64;
+    area.self_ptr.store(address, Ordering::Relaxed);
+    area.cpu_id.store(cpu as u64, Ordering::Relaxed);
+    unsafe {
+        Msr::new(IA32_GS_BASE).write(address);
+        Msr::new(IA32_KERNEL_GS_BASE).write(address);
+    }
+}
+
+/// Number of the current CPU
+#[inline(always)]
+pub fn cpu_id() -> usize {
+    let id: usize;
+    unsafe {
+        asm!("mov {}, gs:[8]", out(reg) id, options(nostack, preserves_flags, readonly));
+    }
+    id
+}
+
+/// The area of the current CPU
+#[inline(always)]
+pub fn area() -> &'static CpuArea {
+    let area: *const CpuArea;
+    unsafe {
+        asm!("mov {}, gs:[0]", out(reg) area, options(nostack, preserves_flags, readonly));
+        &*area
+    }
+}
+
+/// The area of CPU `cpu`
+#[allow(dead_code)]
+pub fn area_of(cpu: usize) -> &'static CpuArea {
+    &AREAS[cpu]
+}
+
+/*
+ * One instance of `T` per CPU, see `percpu!`.
+ *
+ * All threads of a CPU share its instance, and a thread may be preempted by
+ * another one while it holds a reference from `get`. So a PerCpu is only Sync
+ * if T is, which is what the array gives us without an unsafe impl. Types that
+ * are not Sync, like an UnsafeCell that is only touched with interrupts
+ * disabled, need a wrapper that upholds that rule and asserts Sync itself.
+ */
+pub struct PerCpu<T> {
+    values: [T; MAX_CPUS],
+}
+
+impl<T> PerCpu<T> {
+    pub const fn new(values: [T; MAX_CPUS]) -> Self {
+        PerCpu { values }
+    }
+
+    /// The instance of the current CPU. The thread must not move to another
+    /// CPU while using it, and a value that is also used by interrupt
+    /// handlers must only be touched with interrupts disabled.
+    #[inline(always)]
+    pub fn get(&self) -> &T {
+        &self.values[cpu_id()]
+    }
+
+    /// The instance of CPU `cpu`. It races with the code on that CPU, so
+    /// even owners of a PerCpu that is not Sync may only do this if T is.
+    pub fn on(&self, cpu: usize) -> &T
+    where
+        T: Sync,
+    {
+        &self.values[cpu]
+    }
+}
+
+/// Declare a per-CPU static, initialized with the same constant on every CPU:
+///
+///     percpu! { static COUNTER: AtomicU64 = AtomicU64::new(0); }
+///     COUNTER.get().fetch_add(1, Ordering::Relaxed);
+#[macro_export]
+macro_rules! percpu {
+    ($(#[$attr:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;) => {
+        $(#[$attr])*
+        $vis static $name: $crate::smp::percpu::PerCpu<$ty> = {
+            const INIT: $ty = $init;
+            $crate::smp::percpu::PerCpu::new([INIT; $crate::drivers::apic::MAX_CPUS])
+        };
+    };
+}
+
+#[test_case]
+fn percpu_area_matches_cpu() {
+    crate::percpu! { static SLOT: AtomicU64 = AtomicU64::new(0); }
+    SLOT.get().store(7, Ordering::Relaxed);
+    assert_eq!(SLOT.on(cpu_id()).load(Ordering::Relaxed), 7);
+    assert_eq!(area().cpu_id.load(Ordering::Relaxed) as usize, cpu_id());
+}
@@ -0,0 +1,146 @@
+/*
+ * TLB shootdown.
+ *
+ * Every CPU caches translations in its own TLB, so after a mapping changed
+ * the stale entries must be invalidated on all CPUs, not only on the one that
+ * changed the page table. The initiator flushes its own TLB, publishes the
+ * pages in the shared request, flags every other CPU and sends it a
+ * TLB_SHOOTDOWN IPI, then waits until all of them acknowledged. Requests are
+ * serialized by SHOOTDOWN_LOCK, which also keeps the initiator from being
+ * preempted while the other CPUs wait for it. Up to MAX_BATCH pages go in one request, larger
+ * batches flush the whole TLB instead.
+ */
+use super::ipi::{self, TLB_SHOOTDOWN_VECTOR};
+use super::{cpu_count, other_cpus};
+use crate::sync::SpinLock;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use x86_64::instructions::tlb;
+use x86_64::VirtAddr;
+
+pub const MAX_BATCH: usize = 32;
+// request page count meaning "flush everything"
+const FLUSH_ALL: usize = usize::MAX;
+
+static SHOOTDOWN_LOCK: SpinLock<()> = SpinLock::new(());
+const NO_PAGE: AtomicU64 = AtomicU64::new(0);
+static REQUEST_PAGES: [AtomicU64; MAX_BATCH] = [NO_PAGE; MAX_BATCH];
+static REQUEST_COUNT: AtomicUsize = AtomicUsize::new(0);
+// CPUs that did not acknowledge the current request yet
+static PENDING_ACKS: AtomicUsize = AtomicUsize::new(0);
+
+crate::percpu! { static REQUESTED: AtomicBool = AtomicBool::new(false); }
+// page count of the last request this CPU carried out
+crate::percpu! { static LAST_HANDLED: AtomicUsize = AtomicUsize::new(0); }
+
+/// Pages whose translations changed, to be invalidated together
+pub struct TlbBatch {
+    pages: [u64; MAX_BATCH],
+    count: usize,
+}
+
+impl TlbBatch {
+    pub const fn new() -> Self {
+        TlbBatch {
+            pages: [0; MAX_BATCH],
+            count: 0,
+        }
+    }
+
+    pub fn add(&mut self, page: VirtAddr) {
+        if self.count < MAX_BATCH {
+            self.pages[self.count] = page.as_u64();
+            self.count += 1;
+        } else {
+            self.count = FLUSH_ALL;
+        }
+    }
+
+    /// Invalidate the pages on all CPUs
+    pub fn flush(self) {
+        match self.count {
+            0 => {}
+            FLUSH_ALL => shootdown(&[], true),
+            count => shootdown(&self.pages[..count], false),
+        }
+    }
+}
+
+/// Invalidate one page on all CPUs
+#[allow(dead_code)]
+pub fn flush_page(page: VirtAddr) {
+    shootdown(&[page.as_u64()], false);
+}
+
+/// Invalidate all non-global TLB entries on all CPUs
+#[allow(dead_code)]
+pub fn flush_all() {
+    shootdown(&[], true);
+}
+
+fn flush_local(pages: &[u64], all: bool) {
+    if all {
+        tlb::flush_all();
+    } else {
+        for &page in pages {
+            tlb::flush(VirtAddr::new(page));
+        }
+    }
+}
+
+fn shootdown(pages: &[u64], all: bool) {
+    flush_local(pages, all);
+    if cpu_count() == 1 {
+        return;
+    }
+    // serve requests of other initiators while waiting for the lock
+    let mut guard = None;
+    ipi::wait_until(|| {
+        guard = SHOOTDOWN_LOCK.try_lock();
+        guard.is_some()
+    });
+
+    for (slot, &page) in REQUEST_PAGES.iter().zip(pages) {
+        slot.store(page, Ordering::Relaxed);
+    }
+    REQUEST_COUNT.store(if all { FLUSH_ALL } else { pages.len() }, Ordering::Relaxed);
+    PENDING_ACKS.store(other_cpus().count(), Ordering::Release);
+    for cpu in other_cpus() {
+        REQUESTED.on(cpu).store(true, Ordering::Release);
+        ipi::send(cpu, TLB_SHOOTDOWN_VECTOR);
+    }
+    ipi::wait_until(|| PENDING_ACKS.load(Ordering::Acquire) == 0);
+    drop(guard);
+}
+
+/// Carry out a shootdown request for this CPU, if there is one
+pub(super) fn handle_pending() {
+    if !REQUESTED.get().swap(false, Ordering::AcqRel) {
+        return;
+    }
+    let count = REQUEST_COUNT.load(Ordering::Relaxed);
+    if count == FLUSH_ALL {
+        flush_local(&[], true);
+    } else {
+        for slot in REQUEST_PAGES[..count].iter() {
+            tlb::flush(VirtAddr::new(slot.load(Ordering::Relaxed)));
+        }
+    }
+    LAST_HANDLED.get().store(count, Ordering::Relaxed);
+    PENDING_ACKS.fetch_sub(1, Ordering::Release);
+}
+
+#[test_case]
+fn shootdown_reaches_other_cpus() {
+    let mut batch = TlbBatch::new();
+    for i in 0..(MAX_BATCH as u64 + 1) {
+        batch.add(VirtAddr::new(0x4444_0000_0000 + i * 4096));
+    }
+    batch.flush();
+    for cpu in other_cpus() {
+        assert_eq!(LAST_HANDLED.on(cpu).load(Ordering::Relaxed), FLUSH_ALL);
+    }
+    flush_page(VirtAddr::new(0x4444_0000_0000));
+    for cpu in other_cpus() {
+        assert_eq!(LAST_HANDLED.on(cpu).load(Ordering::Relaxed), 1);
+    }
+}
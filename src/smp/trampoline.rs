@@ -0,0 +1,182 @@
+/*
+ * Real-mode entry code for the application processors.
+ *
+ * A processor started by a SIPI begins in real mode at CS = page << 8, IP = 0.
+ * The code below is copied to such a page below 1 MiB, which is also identity
+ * mapped in the kernel page table. It goes straight from real mode to long
+ * mode: load a temporary GDT, enable PAE, load the kernel's CR3, set
+ * EFER.LME/NXE and turn on protection and paging at once, then far jump into
+ * the 64-bit code segment. The code is position independent except for the
+ * GDT base and the far jump target, which `install` patches along with the
+ * rest of the parameters.
+ *
+ * All APs are started through the same page. The parameters name the APIC id
+ * they are meant for, and an AP takes them by clearing that owner word with a
+ * cmpxchg. An AP that comes up late, after the BSP gave up on it with
+ * `withdraw` and set up the page for the next CPU, finds an owner that is not
+ * its own and parks itself instead of running as the next CPU.
+ */
+use core::{mem, ptr};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::{PhysAddr, VirtAddr};
+
+global_asm!(
+    r#"
+    .section .text
+    .code16
+    .align 16
+    .global ap_trampoline_start
+ap_trampoline_start:
+    cli
+    cld
+    mov %cs, %ax
+    mov %ax, %ds
+    lgdtl .Ltramp_gdtr - ap_trampoline_start
+    mov $0xa0, %eax                 # CR4.PAE | CR4.PGE
+    mov %eax, %cr4
+    movl .Ltramp_cr3 - ap_trampoline_start, %eax
+    mov %eax, %cr3
+    mov $0xc0000080, %ecx           # IA32_EFER
+    rdmsr
+    or $0x900, %eax                 # LME | NXE
+    wrmsr
+    mov $0x80010001, %eax           # CR0.PG | CR0.WP | CR0.PE
+    mov %eax, %cr0
+    ljmpl *.Ltramp_lm_jump - ap_trampoline_start
+
+    .code64
+    .global ap_trampoline_long_mode
+ap_trampoline_long_mode:
+    xor %eax, %eax
+    mov %ax, %ds
+    mov %ax, %es
+    mov %ax, %ss
+    mov %ax, %fs
+    mov %ax, %gs
+    mov $1, %eax
+    cpuid
+    shr $24, %ebx                   # our initial APIC id
+    lea 1(%rbx), %rax
+    xor %edx, %edx
+    lock cmpxchg %rdx, .Ltramp_owner(%rip)
+    jne .Ltramp_park
+    mov .Ltramp_stack(%rip), %rsp
+    mov .Ltramp_cpu(%rip), %rdi
+    mov .Ltramp_entry(%rip), %rax
+    xor %ebp, %ebp
+    push $0                         # no return address, keeps the stack aligned
+    jmp *%rax
+.Ltramp_park:
+    cli
+    hlt
+    jmp .Ltramp_park
+
+    .align 8
+    .global ap_trampoline_params
+ap_trampoline_params:
+.Ltramp_gdtr:
+    .word 23
+    .long 0
+    .word 0
+.Ltramp_lm_jump:
+    .long 0
+    .word 0x08
+    .word 0
+.Ltramp_cr3:
+    .quad 0
+.Ltramp_stack:
+    .quad 0
+.Ltramp_entry:
+    .quad 0
+.Ltramp_cpu:
+    .quad 0
+.Ltramp_gdt:
+    .quad 0
+    .quad 0x00af9a000000ffff        # 64-bit code
+    .quad 0x00cf92000000ffff        # data
+.Ltramp_owner:
+    .quad 0                         # APIC id + 1 of the AP to start, 0 once taken
+    .global ap_trampoline_end
+ap_trampoline_end:
+"#
+);
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_long_mode: u8;
+    static ap_trampoline_params: u8;
+    static ap_trampoline_end: u8;
+}
+
+/// Mirrors the parameter block at the end of the trampoline
+#[repr(C, packed)]
+struct Params {
+    gdtr_limit: u16,
+    gdtr_base: u32,
+    _pad0: u16,
+    long_mode_offset: u32,
+    long_mode_selector: u16,
+    _pad1: u16,
+    cr3: u64,
+    stack: u64,
+    entry: u64,
+    cpu: u64,
+    gdt: [u64; 3],
+    owner: u64,
+}
+
+fn offset_of(symbol: &u8) -> usize {
+    symbol as *const u8 as usize - unsafe { &ap_trampoline_start as *const u8 as usize }
+}
+
+/// The owner word of the trampoline at `vaddr`
+unsafe fn owner(vaddr: VirtAddr) -> &'static AtomicU64 {
+    // the owner is the last field of the parameters
+    let offset = offset_of(&ap_trampoline_params) + mem::size_of::<Params>() - mem::size_of::<u64>();
+    &*(vaddr + offset).as_ptr::<AtomicU64>()
+}
+
+/// Copy the trampoline to the page at `paddr`, reachable at `vaddr`, and set
+/// its parameters for starting `cpu` with APIC id `apic_id` on `stack` with
+/// `entry`. The real mode code only reaches 4 GiB, so `cr3` must lie below.
+pub unsafe fn install(
+    paddr: PhysAddr,
+    vaddr: VirtAddr,
+    cr3: PhysAddr,
+    apic_id: u8,
+    cpu: usize,
+    stack: u64,
+    entry: u64,
+) {
+    let len = offset_of(&ap_trampoline_end);
+    assert!(len <= 4096, "AP trampoline does not fit in a page");
+    debug_assert!(paddr.as_u64() < 0x10_0000 && cr3.as_u64() < 1 << 32);
+    ptr::copy_nonoverlapping(&ap_trampoline_start as *const u8, vaddr.as_mut_ptr(), len);
+
+    let base = paddr.as_u64() as u32;
+    let params_offset = offset_of(&ap_trampoline_params);
+    let params = Params {
+        gdtr_limit: 3 * 8 - 1,
+        gdtr_base: base + (params_offset + 48) as u32,
+        _pad0: 0,
+        long_mode_offset: base + offset_of(&ap_trampoline_long_mode) as u32,
+        long_mode_selector: 0x08,
+        _pad1: 0,
+        cr3: cr3.as_u64(),
+        stack,
+        entry,
+        cpu: cpu as u64,
+        gdt: [0, 0x00af_9a00_0000_ffff, 0x00cf_9200_0000_ffff],
+        owner: 0,
+    };
+    ptr::write_volatile((vaddr + params_offset).as_mut_ptr::<Params>(), params);
+    // open the parameters to the AP only once they are complete
+    owner(vaddr).store(apic_id as u64 + 1, Ordering::Release);
+}
+
+/// Give up on the AP the trampoline at `vaddr` was installed for. Returns
+/// false if it already took its parameters, it is on its way then and the
+/// page must not be reused before it checked in.
+pub unsafe fn withdraw(vaddr: VirtAddr) -> bool {
+    owner(vaddr).swap(0, Ordering::AcqRel) != 0
+}
@@ -0,0 +1,95 @@
+/*
+ * Condition variable used together with sync::Mutex.
+ */
+use super::{might_sleep, MutexGuard, WaitQueue};
+use crate::task::scheduler;
+use crate::time::{Duration, Instant};
+
+#[allow(dead_code)]
+pub struct Condvar {
+    waiters: WaitQueue,
+}
+
+#[allow(dead_code)]
+impl Condvar {
+    pub const fn new() -> Self {
+        Condvar {
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Unlock the mutex, sleep until notified and lock it again. May return
+    /// spuriously, callers re-check their condition.
+    #[track_caller]
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        self.wait_deadline(guard, None).0
+    }
+
+    /// Like `wait`, but return after `timeout` at the latest. The flag tells
+    /// whether the wait timed out.
+    #[track_caller]
+    pub fn wait_timeout<'a, T>(&self, guard: MutexGuard<'a, T>, timeout: Duration) -> (MutexGuard<'a, T>, bool) {
+        self.wait_deadline(guard, Some(Instant::now() + timeout))
+    }
+
+    #[track_caller]
+    fn wait_deadline<'a, T>(&self, guard: MutexGuard<'a, T>, deadline: Option<Instant>) -> (MutexGuard<'a, T>, bool) {
+        might_sleep();
+        let mutex = guard.mutex;
+        let id = scheduler::current_id();
+        // queue up before unlocking, so a notify right after is not lost
+        self.waiters.enqueue(id);
+        drop(guard);
+        match deadline {
+            Some(deadline) => scheduler::park_until(deadline),
+            None => scheduler::park(),
+        }
+        // still queued means nobody notified us, but park also returns
+        // spuriously, so only the clock tells whether the deadline passed
+        let notified = !self.waiters.remove(id);
+        let timed_out = !notified && deadline.map_or(false, |deadline| Instant::now() >= deadline);
+        (mutex.lock(), timed_out)
+    }
+
+    pub fn notify_one(&self) {
+        self.waiters.wake_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.waiters.wake_all();
+    }
+}
+
+#[test_case]
+fn condvar_hands_over_value() {
+    use super::Mutex;
+    use alloc::sync::Arc;
+
+    let pair = Arc::new((Mutex::new(None), Condvar::new()));
+    let producer = pair.clone();
+    let handle = crate::task::spawn("producer", move || {
+        let (value, ready) = &*producer;
+        *value.lock() = Some(7);
+        ready.notify_one();
+    });
+    let (value, ready) = &*pair;
+    let mut guard = value.lock();
+    while guard.is_none() {
+        guard = ready.wait(guard);
+    }
+    assert_eq!(*guard, Some(7));
+    drop(guard);
+    handle.join();
+}
+
+#[test_case]
+fn wait_timeout_reports_timeout() {
+    use super::Mutex;
+
+    let mutex = Mutex::new(());
+    let ready = Condvar::new();
+    let start = Instant::now();
+    let (_guard, timed_out) = ready.wait_timeout(mutex.lock(), Duration::from_millis(10));
+    assert!(timed_out);
+    assert!(start.elapsed() >= Duration::from_millis(10));
+}
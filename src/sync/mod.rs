@@ -0,0 +1,37 @@
+/*
+ * Sleeping synchronization primitives.
+ *
+ * Unlike spin::Mutex, these park the current thread while they wait, so they
+ * may only be used from thread context, with interrupts enabled and no
+ * spinlock held. `might_sleep` checks this in debug builds. All of them are
+ * built on `WaitQueue`, which parks and wakes threads via the scheduler.
+ */
+pub mod condvar;
+pub mod mutex;
+pub mod rwlock;
+pub mod semaphore;
+pub mod spinlock;
+pub mod wait_queue;
+
+pub use condvar::Condvar;
+pub use mutex::{Mutex, MutexGuard};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use semaphore::Semaphore;
+pub use spinlock::{SpinLock, SpinLockGuard};
+pub use wait_queue::WaitQueue;
+
+use crate::task::scheduler;
+use crate::interrupts::{bottom_half, irq};
+use x86_64::instructions::interrupts;
+
+/// Complain if the current context must not block
+#[allow(dead_code)]
+#[track_caller]
+pub fn might_sleep() {
+    if cfg!(debug_assertions) {
+        assert!(!irq::in_interrupt(), "sleeping in an interrupt handler");
+        assert!(interrupts::are_enabled(), "sleeping with interrupts disabled");
+        assert!(scheduler::preempt_count() == 0, "sleeping while holding a spinlock");
+        assert!(!bottom_half::in_bottom_half(), "sleeping in a bottom half");
+    }
+}
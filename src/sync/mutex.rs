@@ -0,0 +1,286 @@
+/*
+ * Mutex that puts waiting threads to sleep instead of spinning.
+ *
+ * The mutex records its owner so that it can do priority inheritance: a
+ * thread that blocks on the mutex lends its scheduling priority to the owner
+ * until the owner unlocks, see the pi_* hooks of the scheduler. Otherwise a
+ * medium priority thread could keep the owner, and thereby a high priority
+ * waiter, from running indefinitely.
+ */
+use super::{might_sleep, WaitQueue};
+use crate::task::scheduler;
+use crate::task::thread::ThreadId;
+use crate::time::{Duration, Instant};
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+
+// owner value of an unlocked mutex, otherwise it is the owner's id + 1
+const UNLOCKED: u64 = 0;
+// owner value when locked before the scheduler runs
+const NO_THREAD: u64 = u64::MAX;
+
+#[allow(dead_code)]
+pub struct Mutex<T> {
+    owner: AtomicU64,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+#[allow(dead_code)]
+pub struct MutexGuard<'a, T> {
+    pub(super) mutex: &'a Mutex<T>,
+}
+
+#[allow(dead_code)]
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            owner: AtomicU64::new(UNLOCKED),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    fn key(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    /// Try to take the mutex for `me`, returns the current owner on failure
+    fn acquire(&self, me: Option<ThreadId>) -> Result<(), u64> {
+        let value = me.map_or(NO_THREAD, |id| id.as_u64() + 1);
+        self.owner
+            .compare_exchange(UNLOCKED, value, Ordering::Acquire, Ordering::Relaxed)
+            .map(|_| ())?;
+        if let Some(me) = me {
+            // threads still waiting now lend their priority to us
+            if !self.waiters.is_empty() {
+                scheduler::pi_take_over(self.key(), &self.waiters.waiters());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        let me = scheduler::try_current_id();
+        self.acquire(me).ok().map(|_| MutexGuard { mutex: self })
+    }
+
+    /// Lock the mutex, sleeping while it is held by another thread
+    #[track_caller]
+    pub fn lock(&self) -> MutexGuard<T> {
+        self.lock_deadline(None).unwrap()
+    }
+
+    /// Like `lock`, but give up after `timeout`
+    #[track_caller]
+    pub fn lock_timeout(&self, timeout: Duration) -> Option<MutexGuard<T>> {
+        self.lock_deadline(Some(Instant::now() + timeout))
+    }
+
+    #[track_caller]
+    fn lock_deadline(&self, deadline: Option<Instant>) -> Option<MutexGuard<T>> {
+        might_sleep();
+        let me = scheduler::try_current_id();
+        let mut blocked = false;
+        let locked = self.waiters.wait_until_deadline(
+            || match self.acquire(me) {
+                Ok(()) => true,
+                Err(owner) => {
+                    if me.is_some() && owner != NO_THREAD && owner != UNLOCKED {
+                        scheduler::pi_block_on(self.key(), ThreadId::from_u64(owner - 1));
+                        blocked = true;
+                    }
+                    false
+                }
+            },
+            deadline,
+        );
+        if blocked {
+            scheduler::pi_unblock();
+        }
+        if locked {
+            Some(MutexGuard { mutex: self })
+        } else {
+            None
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        let mutex = self.mutex;
+        interrupts::without_interrupts(|| {
+            if mutex.owner.load(Ordering::Relaxed) != NO_THREAD {
+                scheduler::pi_release(mutex.key());
+            }
+            mutex.owner.store(UNLOCKED, Ordering::Release);
+            mutex.waiters.wake_one();
+        });
+    }
+}
+
+#[test_case]
+fn mutex_serializes_threads() {
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+
+    let counter = Arc::new(Mutex::new(0u64));
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let counter = counter.clone();
+            crate::task::spawn("mutex test", move || {
+                for _ in 0..100 {
+                    let mut guard = counter.lock();
+                    let value = *guard;
+                    crate::task::yield_now(); // let the others run into the lock
+                    *guard = value + 1;
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join();
+    }
+    assert_eq!(*counter.lock(), 400);
+}
+
+#[test_case]
+fn lock_timeout_expires() {
+    let mutex = Mutex::new(());
+    let _guard = mutex.lock();
+    let start = Instant::now();
+    assert!(mutex.lock_timeout(Duration::from_millis(10)).is_none());
+    assert!(start.elapsed() >= Duration::from_millis(10));
+}
+
+#[cfg(test)]
+fn busy_wait(duration: Duration) {
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        core::sync::atomic::spin_loop_hint();
+    }
+}
+
+#[cfg(test)]
+fn wait_for(mut condition: impl FnMut() -> bool) {
+    let start = Instant::now();
+    while !condition() {
+        assert!(start.elapsed() < Duration::from_secs(2), "timed out");
+        crate::task::sleep(Duration::from_millis(1));
+    }
+}
+
+/*
+ * Classic priority inversion: low holds the mutex high wants, medium hogs the
+ * CPU. Without inheritance high gets the mutex only after medium finished.
+ */
+#[test_case]
+fn priority_inversion_is_resolved() {
+    use crate::task::{self, SchedPolicy};
+    use alloc::sync::Arc;
+    use core::sync::atomic::AtomicBool;
+
+    static LOW_LOCKED: AtomicBool = AtomicBool::new(false);
+    static HIGH_ACQUIRED: AtomicU64 = AtomicU64::new(0);
+    static MEDIUM_DONE: AtomicU64 = AtomicU64::new(0);
+
+    let me = task::current_id();
+    // the test thread only sets things up, it must not compete for the CPU
+    task::set_policy(me, SchedPolicy::Fifo { priority: 50 });
+
+    let mutex = Arc::new(Mutex::new(()));
+    let low_mutex = mutex.clone();
+    let low = task::spawn_with_policy("pi-low", SchedPolicy::Priority { priority: 1 }, move || {
+        let _guard = low_mutex.lock();
+        LOW_LOCKED.store(true, Ordering::SeqCst);
+        busy_wait(Duration::from_millis(20));
+    });
+    wait_for(|| LOW_LOCKED.load(Ordering::SeqCst));
+
+    let medium = task::spawn_with_policy("pi-medium", SchedPolicy::Priority { priority: 20 }, || {
+        busy_wait(Duration::from_millis(200));
+        MEDIUM_DONE.store(Instant::now().as_nanos(), Ordering::SeqCst);
+    });
+    let high_mutex = mutex.clone();
+    let high = task::spawn_with_policy("pi-high", SchedPolicy::Priority { priority: 30 }, move || {
+        let _guard = high_mutex.lock();
+        HIGH_ACQUIRED.store(Instant::now().as_nanos(), Ordering::SeqCst);
+    });
+
+    high.join();
+    medium.join();
+    low.join();
+    task::set_policy(me, SchedPolicy::default());
+    assert!(HIGH_ACQUIRED.load(Ordering::SeqCst) < MEDIUM_DONE.load(Ordering::SeqCst));
+}
+
+/// high waits for B held by mid, which waits for A held by low: low must run
+/// with high's priority
+#[test_case]
+fn priority_inheritance_follows_chains() {
+    use crate::task::{self, SchedPolicy};
+    use alloc::sync::Arc;
+    use core::sync::atomic::AtomicBool;
+
+    static LOW_LOCKED: AtomicBool = AtomicBool::new(false);
+    static RELEASE: AtomicBool = AtomicBool::new(false);
+
+    let me = task::current_id();
+    task::set_policy(me, SchedPolicy::Fifo { priority: 50 });
+
+    let a = Arc::new(Mutex::new(()));
+    let b = Arc::new(Mutex::new(()));
+    let low_a = a.clone();
+    let low = task::spawn_with_policy("pi-low", SchedPolicy::Priority { priority: 1 }, move || {
+        let _a = low_a.lock();
+        LOW_LOCKED.store(true, Ordering::SeqCst);
+        while !RELEASE.load(Ordering::SeqCst) {
+            task::yield_now();
+        }
+    });
+    wait_for(|| LOW_LOCKED.load(Ordering::SeqCst));
+
+    let (mid_a, mid_b) = (a.clone(), b.clone());
+    let mid = task::spawn_with_policy("pi-mid", SchedPolicy::Priority { priority: 10 }, move || {
+        let _b = mid_b.lock();
+        let _a = mid_a.lock();
+    });
+    let low_id = low.id();
+    wait_for(|| scheduler::policy_of(low_id) == Some(SchedPolicy::Priority { priority: 10 }));
+
+    let high_b = b.clone();
+    let high = task::spawn_with_policy("pi-high", SchedPolicy::Priority { priority: 30 }, move || {
+        let _b = high_b.lock();
+    });
+    wait_for(|| scheduler::policy_of(low_id) == Some(SchedPolicy::Priority { priority: 30 }));
+
+    RELEASE.store(true, Ordering::SeqCst);
+    high.join();
+    mid.join();
+    assert_eq!(scheduler::policy_of(low_id), Some(SchedPolicy::Priority { priority: 1 }));
+    low.join();
+    task::set_policy(me, SchedPolicy::default());
+}
@@ -0,0 +1,203 @@
+/*
+ * Reader-writer lock that sleeps while waiting.
+ *
+ * `state` counts the readers, WRITER marks an exclusive owner. Waiting writers
+ * hold off new readers so that a steady stream of readers cannot starve them.
+ */
+use super::{might_sleep, WaitQueue};
+use crate::time::{Duration, Instant};
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+const WRITER: usize = 1 << (usize::MAX.count_ones() - 1);
+
+#[allow(dead_code)]
+pub struct RwLock<T> {
+    state: AtomicUsize,
+    waiting_writers: AtomicUsize,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for RwLock<T> {}
+unsafe impl<T: Send + Sync> Sync for RwLock<T> {}
+
+#[allow(dead_code)]
+pub struct RwLockReadGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+#[allow(dead_code)]
+pub struct RwLockWriteGuard<'a, T> {
+    lock: &'a RwLock<T>,
+}
+
+#[allow(dead_code)]
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        RwLock {
+            state: AtomicUsize::new(0),
+            waiting_writers: AtomicUsize::new(0),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<T>> {
+        let mut state = self.state.load(Ordering::Relaxed);
+        loop {
+            if state & WRITER != 0 || self.waiting_writers.load(Ordering::Relaxed) != 0 {
+                return None;
+            }
+            match self.state.compare_exchange_weak(state, state + 1, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => return Some(RwLockReadGuard { lock: self }),
+                Err(current) => state = current,
+            }
+        }
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<T>> {
+        self.state
+            .compare_exchange(0, WRITER, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| RwLockWriteGuard { lock: self })
+    }
+
+    #[track_caller]
+    pub fn read(&self) -> RwLockReadGuard<T> {
+        self.read_deadline(None).unwrap()
+    }
+
+    #[track_caller]
+    pub fn read_timeout(&self, timeout: Duration) -> Option<RwLockReadGuard<T>> {
+        self.read_deadline(Some(Instant::now() + timeout))
+    }
+
+    #[track_caller]
+    fn read_deadline(&self, deadline: Option<Instant>) -> Option<RwLockReadGuard<T>> {
+        might_sleep();
+        if let Some(guard) = self.try_read() {
+            return Some(guard);
+        }
+        let mut guard = None;
+        self.waiters.wait_until_deadline(
+            || {
+                guard = self.try_read();
+                guard.is_some()
+            },
+            deadline,
+        );
+        guard
+    }
+
+    #[track_caller]
+    pub fn write(&self) -> RwLockWriteGuard<T> {
+        self.write_deadline(None).unwrap()
+    }
+
+    #[track_caller]
+    pub fn write_timeout(&self, timeout: Duration) -> Option<RwLockWriteGuard<T>> {
+        self.write_deadline(Some(Instant::now() + timeout))
+    }
+
+    #[track_caller]
+    fn write_deadline(&self, deadline: Option<Instant>) -> Option<RwLockWriteGuard<T>> {
+        might_sleep();
+        if let Some(guard) = self.try_write() {
+            return Some(guard);
+        }
+        self.waiting_writers.fetch_add(1, Ordering::Relaxed);
+        let mut guard = None;
+        self.waiters.wait_until_deadline(
+            || {
+                guard = self.try_write();
+                guard.is_some()
+            },
+            deadline,
+        );
+        self.waiting_writers.fetch_sub(1, Ordering::Relaxed);
+        if guard.is_none() {
+            // readers held off by us may go ahead now
+            self.waiters.wake_all();
+        }
+        guard
+    }
+}
+
+impl<'a, T> Deref for RwLockReadGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for RwLockReadGuard<'a, T> {
+    fn drop(&mut self) {
+        if self.lock.state.fetch_sub(1, Ordering::Release) == 1 {
+            self.lock.waiters.wake_all();
+        }
+    }
+}
+
+impl<'a, T> Deref for RwLockWriteGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for RwLockWriteGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<'a, T> Drop for RwLockWriteGuard<'a, T> {
+    fn drop(&mut self) {
+        self.lock.state.store(0, Ordering::Release);
+        self.lock.waiters.wake_all();
+    }
+}
+
+#[test_case]
+fn readers_share_the_lock() {
+    use alloc::sync::Arc;
+
+    let lock = Arc::new(RwLock::new(5));
+    let first = lock.read();
+    let second = lock.try_read().expect("second reader blocked");
+    assert_eq!(*first + *second, 10);
+    assert!(lock.try_write().is_none());
+    // a reader on another thread gets in while we hold ours
+    let other = lock.clone();
+    crate::task::spawn("reader", move || assert_eq!(*other.read(), 5)).join();
+    drop(first);
+    drop(second);
+    assert!(lock.try_write().is_some());
+}
+
+#[test_case]
+fn writer_excludes_others() {
+    use alloc::sync::Arc;
+    use core::sync::atomic::AtomicBool;
+
+    let lock = Arc::new(RwLock::new(0));
+    let read = Arc::new(AtomicBool::new(false));
+    let mut guard = lock.write();
+    assert!(lock.try_read().is_none());
+    assert!(lock.try_write().is_none());
+    let (other, done) = (lock.clone(), read.clone());
+    let handle = crate::task::spawn("reader", move || {
+        assert_eq!(*other.read(), 1);
+        done.store(true, Ordering::Relaxed);
+    });
+    crate::task::sleep(Duration::from_millis(10));
+    assert!(!read.load(Ordering::Relaxed));
+    *guard = 1;
+    drop(guard);
+    handle.join();
+    assert!(read.load(Ordering::Relaxed));
+}
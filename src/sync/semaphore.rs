@@ -0,0 +1,76 @@
+/*
+ * Counting semaphore.
+ */
+use super::{might_sleep, WaitQueue};
+use crate::time::Duration;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[allow(dead_code)]
+pub struct Semaphore {
+    count: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+#[allow(dead_code)]
+impl Semaphore {
+    pub const fn new(count: usize) -> Self {
+        Semaphore {
+            count: AtomicUsize::new(count),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Take one unit without waiting
+    pub fn try_acquire(&self) -> bool {
+        let mut count = self.count.load(Ordering::Relaxed);
+        while count > 0 {
+            match self.count.compare_exchange_weak(count, count - 1, Ordering::Acquire, Ordering::Relaxed) {
+                Ok(_) => return true,
+                Err(current) => count = current,
+            }
+        }
+        false
+    }
+
+    /// Take one unit, sleeping until one is available
+    #[track_caller]
+    pub fn acquire(&self) {
+        might_sleep();
+        self.waiters.wait_until(|| self.try_acquire());
+    }
+
+    /// Like `acquire`, but give up after `timeout`. Returns whether a unit
+    /// was taken.
+    #[track_caller]
+    pub fn acquire_timeout(&self, timeout: Duration) -> bool {
+        might_sleep();
+        self.waiters.wait_until_timeout(|| self.try_acquire(), timeout)
+    }
+
+    /// Return one unit and wake a waiter
+    pub fn release(&self) {
+        self.count.fetch_add(1, Ordering::Release);
+        self.waiters.wake_one();
+    }
+
+    pub fn available(&self) -> usize {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+#[test_case]
+fn permits_are_counted() {
+    let semaphore = Semaphore::new(2);
+    assert!(semaphore.try_acquire());
+    assert!(semaphore.try_acquire());
+    assert_eq!(semaphore.available(), 0);
+    assert!(!semaphore.try_acquire());
+    assert!(!semaphore.acquire_timeout(Duration::from_millis(5)));
+    semaphore.release();
+    assert_eq!(semaphore.available(), 1);
+    semaphore.acquire();
+    assert_eq!(semaphore.available(), 0);
+    semaphore.release();
+    semaphore.release();
+    assert_eq!(semaphore.available(), 2);
+}
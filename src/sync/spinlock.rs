@@ -0,0 +1,66 @@
+/*
+ * A spin::Mutex that disables preemption while it is held. Threads must not
+ * be switched out while holding it, otherwise another thread would spin on
+ * the lock for its whole time slice. Holding it also makes `might_sleep` fail.
+ */
+use crate::task::scheduler;
+use core::ops::{Deref, DerefMut};
+
+#[allow(dead_code)]
+pub struct SpinLock<T> {
+    inner: spin::Mutex<T>,
+}
+
+#[allow(dead_code)]
+pub struct SpinLockGuard<'a, T> {
+    guard: Option<spin::MutexGuard<'a, T>>,
+}
+
+#[allow(dead_code)]
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        SpinLock {
+            inner: spin::Mutex::new(value),
+        }
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<T> {
+        scheduler::preempt_disable();
+        SpinLockGuard {
+            guard: Some(self.inner.lock()),
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<SpinLockGuard<T>> {
+        scheduler::preempt_disable();
+        match self.inner.try_lock() {
+            Some(guard) => Some(SpinLockGuard { guard: Some(guard) }),
+            None => {
+                scheduler::preempt_enable();
+                None
+            }
+        }
+    }
+}
+
+impl<'a, T> Deref for SpinLockGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.guard.as_ref().unwrap()
+    }
+}
+
+impl<'a, T> DerefMut for SpinLockGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.guard.as_mut().unwrap()
+    }
+}
+
+impl<'a, T> Drop for SpinLockGuard<'a, T> {
+    fn drop(&mut self) {
+        // unlock before preemption is possible again
+        self.guard.take();
+        scheduler::preempt_enable();
+    }
+}
@@ -0,0 +1,129 @@
+/*
+ * Queue of threads waiting for some condition.
+ *
+ * A waiter first adds itself to the queue and then re-checks its condition
+ * before parking. A wakeup arriving in between is not lost, since unparking a
+ * thread that is not parked yet makes its next park return immediately.
+ * Parking may also return spuriously, so waiters always re-check.
+ */
+use super::might_sleep;
+use crate::task::scheduler;
+use crate::task::thread::ThreadId;
+use crate::time::{Duration, Instant};
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+#[allow(dead_code)]
+pub struct WaitQueue {
+    waiters: Mutex<Vec<ThreadId>>,
+}
+
+#[allow(dead_code)]
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub(super) fn enqueue(&self, id: ThreadId) {
+        interrupts::without_interrupts(|| self.waiters.lock().push(id));
+    }
+
+    /// Snapshot of the waiting threads
+    pub(super) fn waiters(&self) -> Vec<ThreadId> {
+        interrupts::without_interrupts(|| self.waiters.lock().clone())
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        interrupts::without_interrupts(|| self.waiters.lock().is_empty())
+    }
+
+    /// Remove a waiter that gave up, e.g. after a timeout. Returns false if
+    /// it was already dequeued by a wakeup.
+    pub(super) fn remove(&self, id: ThreadId) -> bool {
+        interrupts::without_interrupts(|| {
+            let mut waiters = self.waiters.lock();
+            let len = waiters.len();
+            waiters.retain(|&waiter| waiter != id);
+            waiters.len() != len
+        })
+    }
+
+    /// Wait until `condition` returns true
+    #[track_caller]
+    pub fn wait_until(&self, condition: impl FnMut() -> bool) {
+        self.wait_until_deadline(condition, None);
+    }
+
+    /// Wait until `condition` returns true or `timeout` has passed. Returns
+    /// whether the condition was met.
+    #[track_caller]
+    pub fn wait_until_timeout(&self, condition: impl FnMut() -> bool, timeout: Duration) -> bool {
+        self.wait_until_deadline(condition, Some(Instant::now() + timeout))
+    }
+
+    #[track_caller]
+    pub(super) fn wait_until_deadline(
+        &self,
+        mut condition: impl FnMut() -> bool,
+        deadline: Option<Instant>,
+    ) -> bool {
+        if condition() {
+            return true;
+        }
+        might_sleep();
+        let id = scheduler::current_id();
+        loop {
+            self.enqueue(id);
+            if condition() {
+                self.remove(id);
+                return true;
+            }
+            match deadline {
+                Some(deadline) if Instant::now() >= deadline => {
+                    // pass on a wakeup meant for us to the next waiter
+                    if !self.remove(id) {
+                        self.wake_one();
+                    }
+                    return false;
+                }
+                Some(deadline) => scheduler::park_until(deadline),
+                None => scheduler::park(),
+            }
+            self.remove(id);
+            if condition() {
+                return true;
+            }
+        }
+    }
+
+    /// Wake the longest waiting thread. Returns false if there was none.
+    pub fn wake_one(&self) -> bool {
+        let waiter = interrupts::without_interrupts(|| {
+            let mut waiters = self.waiters.lock();
+            if waiters.is_empty() {
+                None
+            } else {
+                Some(waiters.remove(0))
+            }
+        });
+        match waiter {
+            Some(id) => {
+                scheduler::unpark(id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wake all waiting threads, returns how many there were
+    pub fn wake_all(&self) -> usize {
+        let waiters = interrupts::without_interrupts(|| core::mem::replace(&mut *self.waiters.lock(), Vec::new()));
+        for &id in waiters.iter() {
+            scheduler::unpark(id);
+        }
+        waiters.len()
+    }
+}
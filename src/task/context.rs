@@ -0,0 +1,55 @@
+/*
+ * Context switch between kernel threads.
+ *
+ * Only the callee-saved registers and rflags have to be kept across a switch,
+ * everything else is saved by the compiler around the call. They are pushed on
+ * the old stack, the stack pointer is stored in the old thread and the new
+ * thread's stack pointer is loaded, then the registers are popped in reverse.
+ *
+ * Stack of a switched-out thread, from the saved rsp upwards:
+ *     rflags, r15, r14, r13, r12, rbx, rbp, return address
+ */
+
+/// Number of words pushed by `switch_context` below the return address
+const SAVED_REGISTERS: usize = 7;
+const INITIAL_RFLAGS: u64 = 0x2; // bit 1 is reserved and always set, IF is clear
+
+/// Save the current context, store its stack pointer to `*old_rsp` and resume
+/// the context saved at `new_rsp`.
+#[naked]
+pub unsafe extern "C" fn switch_context(old_rsp: *mut u64, new_rsp: u64) {
+    asm!(
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "pushfq",
+        "mov [rdi], rsp",
+        "mov rsp, rsi",
+        "popfq",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+        options(noreturn)
+    );
+}
+
+/// Prepare a fresh stack so that switching to it "returns" into `entry`.
+/// Returns the stack pointer to pass to `switch_context`.
+pub fn init_stack(stack: &mut [u64], entry: extern "C" fn() -> !) -> u64 {
+    let top = stack.len();
+    // entry is reached by `ret` and must see rsp + 8 aligned to 16 like after a call
+    stack[top - 1] = 0;
+    stack[top - 2] = entry as u64;
+    for word in stack[top - 2 - SAVED_REGISTERS..top - 2].iter_mut() {
+        *word = 0;
+    }
+    stack[top - 2 - SAVED_REGISTERS] = INITIAL_RFLAGS;
+    &stack[top - 2 - SAVED_REGISTERS] as *const u64 as u64
+}
@@ -0,0 +1,217 @@
+/*
+ * Cooperative executor for async tasks.
+ *
+ * A task is a pinned, boxed future. The executor only polls tasks whose waker
+ * was called, their ids wait in `ready_queue`. Wakers may be called from
+ * interrupt handlers, so the queue is only locked with interrupts disabled
+ * and must not allocate there. A task is queued at most once, `spawn` grows the
+ * queue to hold every task and the wakers never push beyond that.
+ * When no task is ready the thread running the executor parks and the wakers
+ * unpark it, so other threads get the CPU meanwhile. Before the scheduler
+ * runs, the CPU halts until the next interrupt instead.
+ */
+use super::scheduler;
+use crate::smp::percpu::NO_THREAD;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll, Waker};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+pub struct Task {
+    id: TaskId,
+    future: Pin<Box<dyn Future<Output = ()>>>,
+}
+
+impl Task {
+    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+        Task {
+            id: TaskId::new(),
+            future: Box::pin(future),
+        }
+    }
+
+    fn poll(&mut self, context: &mut Context) -> Poll<()> {
+        self.future.as_mut().poll(context)
+    }
+}
+
+struct ReadyState {
+    queue: Mutex<VecDeque<TaskId>>,
+    // thread parked in `sleep_if_idle`, NO_THREAD if none
+    sleeper: AtomicU64,
+}
+
+type ReadyQueue = Arc<ReadyState>;
+
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+    ready_queue: ReadyQueue,
+    waker_cache: BTreeMap<TaskId, Arc<TaskWaker>>,
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Executor {
+            tasks: BTreeMap::new(),
+            ready_queue: Arc::new(ReadyState {
+                queue: Mutex::new(VecDeque::new()),
+                sleeper: AtomicU64::new(NO_THREAD),
+            }),
+            waker_cache: BTreeMap::new(),
+        }
+    }
+
+    pub fn spawn(&mut self, task: Task) {
+        let id = task.id;
+        if self.tasks.insert(id, task).is_some() {
+            panic!("task with same ID already in tasks");
+        }
+        let tasks = self.tasks.len();
+        interrupts::without_interrupts(|| {
+            let mut queue = self.ready_queue.queue.lock();
+            let len = queue.len();
+            queue.reserve(tasks - len);
+            queue.push_back(id);
+        });
+    }
+
+    fn pop_ready(&self) -> Option<TaskId> {
+        interrupts::without_interrupts(|| self.ready_queue.queue.lock().pop_front())
+    }
+
+    /// Poll every ready task once
+    fn run_ready_tasks(&mut self) {
+        while let Some(id) = self.pop_ready() {
+            let task = match self.tasks.get_mut(&id) {
+                Some(task) => task,
+                None => continue, // task no longer exists
+            };
+            let ready_queue = &self.ready_queue;
+            let task_waker = self
+                .waker_cache
+                .entry(id)
+                .or_insert_with(|| TaskWaker::new(id, ready_queue.clone()));
+            // wakes from here on must queue the task again
+            task_waker.queued.store(false, Ordering::Release);
+            let waker = Waker::from(task_waker.clone());
+            let mut context = Context::from_waker(&waker);
+            if let Poll::Ready(()) = task.poll(&mut context) {
+                // leftover wakers of the task must not take queue slots
+                task_waker.queued.store(true, Ordering::Release);
+                self.tasks.remove(&id);
+                self.waker_cache.remove(&id);
+            }
+        }
+    }
+
+    /// Run until all tasks have finished
+    pub fn run_until_idle(&mut self) {
+        while !self.tasks.is_empty() {
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.run_ready_tasks();
+            self.sleep_if_idle();
+        }
+    }
+
+    fn sleep_if_idle(&self) {
+        let is_empty = || interrupts::without_interrupts(|| self.ready_queue.queue.lock().is_empty());
+        if let Some(id) = scheduler::try_current_id() {
+            // a wakeup between the check and park() leaves an unpark token,
+            // so park() returns right away
+            self.ready_queue.sleeper.store(id.as_u64(), Ordering::Release);
+            if is_empty() {
+                scheduler::park();
+            }
+            self.ready_queue.sleeper.store(NO_THREAD, Ordering::Release);
+            return;
+        }
+        // An interrupt between the check and the hlt could wake a task and
+        // then go unnoticed until the next interrupt. Check with interrupts
+        // off and enable them atomically with hlt.
+        interrupts::disable();
+        if self.ready_queue.queue.lock().is_empty() {
+            interrupts::enable_and_hlt();
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+struct TaskWaker {
+    id: TaskId,
+    // the task is in the ready queue or finished
+    queued: AtomicBool,
+    ready_queue: ReadyQueue,
+}
+
+impl TaskWaker {
+    fn new(id: TaskId, ready_queue: ReadyQueue) -> Arc<TaskWaker> {
+        Arc::new(TaskWaker {
+            id,
+            queued: AtomicBool::new(false),
+            ready_queue,
+        })
+    }
+
+    fn wake_task(&self) {
+        if self.queued.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        interrupts::without_interrupts(|| self.ready_queue.queue.lock().push_back(self.id));
+        match self.ready_queue.sleeper.load(Ordering::Acquire) {
+            NO_THREAD => {}
+            id => scheduler::unpark(super::thread::ThreadId::from_u64(id)),
+        }
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_task();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.wake_task();
+    }
+}
+
+#[test_case]
+fn tasks_run_to_completion() {
+    use core::sync::atomic::AtomicUsize;
+    static DONE: AtomicUsize = AtomicUsize::new(0);
+
+    async fn number() -> usize {
+        42
+    }
+    async fn add_number() {
+        DONE.fetch_add(number().await, Ordering::SeqCst);
+    }
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(add_number()));
+    executor.spawn(Task::new(add_number()));
+    executor.run_until_idle();
+    assert_eq!(DONE.load(Ordering::SeqCst), 84);
+}
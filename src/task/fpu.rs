@@ -0,0 +1,250 @@
+/*
+ * FPU and SSE state of threads.
+ *
+ * The kernel itself is built soft-float and never touches the x87, MMX or SSE
+ * registers, but threads may use them through inline assembly and code built
+ * for the user target, which enables SSE. Every thread has an FpuState area
+ * the registers are saved to with XSAVE, or FXSAVE if the CPU lacks XSAVE.
+ *
+ * Each CPU remembers whose state is currently in its registers (the owner).
+ * In lazy mode a context switch only sets CR0.TS if the next thread is not
+ * the owner. Its first FPU instruction then raises #NM, whose handler saves
+ * the owner's registers and loads the current thread's. Threads that never
+ * use the FPU never pay for it. In eager mode the state is switched on every
+ * context switch and #NM never fires.
+ *
+ * Threads are pinned to the CPU they were created on. A thread's registers
+ * may therefore stay in its CPU after it was switched out, and only that CPU
+ * ever saves them. Moving threads between CPUs would need to save their state
+ * to their area first.
+ */
+use crate::interrupts::stats;
+use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+use core::arch::x86_64::{__cpuid, __cpuid_count};
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::structures::idt::InterruptStackFrame;
+
+const DEVICE_NOT_AVAILABLE_VECTOR: u8 = 7;
+
+// size of the legacy FXSAVE area
+const FXSAVE_SIZE: usize = 512;
+// XCR0 bits: x87, SSE and AVX state
+const XCR0_X87: u64 = 1 << 0;
+const XCR0_SSE: u64 = 1 << 1;
+const XCR0_AVX: u64 = 1 << 2;
+// initial control words, as set by fninit and at reset
+const INITIAL_FCW: u16 = 0x037f;
+const INITIAL_MXCSR: u32 = 0x1f80;
+
+static USE_XSAVE: AtomicBool = AtomicBool::new(false);
+static AREA_SIZE: AtomicUsize = AtomicUsize::new(FXSAVE_SIZE);
+static EAGER: AtomicBool = AtomicBool::new(false);
+
+crate::percpu! {
+    // area whose state is loaded in the registers of this CPU
+    static OWNER: AtomicPtr<u8> = AtomicPtr::new(ptr::null_mut());
+}
+crate::percpu! {
+    // area of the thread running on this CPU
+    static CURRENT: AtomicPtr<u8> = AtomicPtr::new(ptr::null_mut());
+}
+
+/// Saved FPU, SSE and (if enabled) AVX registers of a thread
+pub struct FpuState {
+    area: NonNull<u8>,
+    size: usize,
+}
+
+// the area is only accessed by the CPU the thread runs on
+unsafe impl Send for FpuState {}
+
+impl FpuState {
+    /// A state with the registers as after reset
+    pub fn new() -> Self {
+        let size = AREA_SIZE.load(Ordering::Relaxed);
+        let area = unsafe { alloc_zeroed(Self::layout(size)) };
+        let area = NonNull::new(area).expect("fpu: out of memory");
+        unsafe {
+            // the XSAVE header stays zero: all components in their initial state
+            ptr::write(area.as_ptr() as *mut u16, INITIAL_FCW);
+            ptr::write(area.as_ptr().add(24) as *mut u32, INITIAL_MXCSR);
+        }
+        FpuState { area, size }
+    }
+
+    fn layout(size: usize) -> Layout {
+        // XSAVE needs 64 byte alignment, FXSAVE 16
+        Layout::from_size_align(size, 64).unwrap()
+    }
+
+    fn as_ptr(&self) -> *mut u8 {
+        self.area.as_ptr()
+    }
+}
+
+impl Drop for FpuState {
+    fn drop(&mut self) {
+        // forget the registers of a CPU that still holds this state
+        for cpu in 0..crate::smp::cpu_count() {
+            let _ = OWNER.on(cpu).compare_exchange(self.as_ptr(), ptr::null_mut(), Ordering::AcqRel, Ordering::Relaxed);
+        }
+        unsafe { dealloc(self.as_ptr(), Self::layout(self.size)) };
+    }
+}
+
+/// Enable the FPU, SSE and XSAVE on this CPU. Run by every CPU before it
+/// runs threads.
+pub fn init() {
+    unsafe {
+        let mut cr0 = Cr0::read();
+        cr0.remove(Cr0Flags::EMULATE_COPROCESSOR);
+        cr0.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+        Cr0::write(cr0);
+
+        let mut cr4 = Cr4::read();
+        // the OS saves SSE state with FXSAVE and handles #XM
+        cr4.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE);
+        let features = __cpuid(1).ecx;
+        let xsave = features & (1 << 26) != 0;
+        if xsave {
+            cr4.insert(Cr4Flags::OSXSAVE);
+        }
+        Cr4::write(cr4);
+
+        if xsave {
+            let supported = __cpuid_count(0xd, 0).eax as u64;
+            let mut xcr0 = XCR0_X87 | XCR0_SSE;
+            if features & (1 << 28) != 0 && supported & XCR0_AVX != 0 {
+                xcr0 |= XCR0_AVX;
+            }
+            asm!("xsetbv", in("ecx") 0u32, in("eax") xcr0 as u32, in("edx") (xcr0 >> 32) as u32);
+            // size of the XSAVE area for the components enabled in XCR0
+            AREA_SIZE.store(__cpuid_count(0xd, 0).ebx as usize, Ordering::Relaxed);
+        }
+        USE_XSAVE.store(xsave, Ordering::Relaxed);
+        asm!("fninit");
+    }
+    set_task_switched(true);
+}
+
+/// Switch the state on every context switch instead of on first use
+#[allow(dead_code)]
+pub fn set_eager(eager: bool) {
+    EAGER.store(eager, Ordering::Relaxed);
+    // the running thread was switched in lazily, load its state right away
+    if eager && !CURRENT.get().load(Ordering::Relaxed).is_null() {
+        interrupts::without_interrupts(take_ownership);
+    }
+}
+
+fn set_task_switched(set: bool) {
+    if set {
+        unsafe { Cr0::write(Cr0::read() | Cr0Flags::TASK_SWITCHED) };
+    } else {
+        unsafe { asm!("clts", options(nomem, nostack)) };
+    }
+}
+
+unsafe fn save(area: *mut u8) {
+    if USE_XSAVE.load(Ordering::Relaxed) {
+        asm!("xsave64 [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack));
+    } else {
+        asm!("fxsave64 [{}]", in(reg) area, options(nostack));
+    }
+}
+
+unsafe fn restore(area: *mut u8) {
+    if USE_XSAVE.load(Ordering::Relaxed) {
+        asm!("xrstor64 [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack));
+    } else {
+        asm!("fxrstor64 [{}]", in(reg) area, options(nostack));
+    }
+}
+
+/// Load the state of the current thread into the registers, saving the
+/// registers of the previous owner first. Interrupts must be disabled.
+fn take_ownership() {
+    let current = CURRENT.get().load(Ordering::Relaxed);
+    let owner = OWNER.get().load(Ordering::Relaxed);
+    set_task_switched(false);
+    if owner == current {
+        return;
+    }
+    unsafe {
+        if !owner.is_null() {
+            save(owner);
+        }
+        restore(current);
+    }
+    OWNER.get().store(current, Ordering::Relaxed);
+}
+
+/// Make `state` the state of the thread running on this CPU
+pub(super) fn set_current(state: &FpuState) {
+    CURRENT.get().store(state.as_ptr(), Ordering::Relaxed);
+}
+
+/// Called by the scheduler before switching to the thread owning `next`
+pub(super) fn switch_to(next: &FpuState) {
+    set_current(next);
+    if EAGER.load(Ordering::Relaxed) {
+        take_ownership();
+    } else {
+        // trap the first FPU instruction unless the registers are already next's
+        set_task_switched(OWNER.get().load(Ordering::Relaxed) != next.as_ptr());
+    }
+}
+
+/// #NM: a thread used the FPU while CR0.TS was set
+pub extern "x86-interrupt" fn device_not_available_handler(stack_frame: &mut InterruptStackFrame) {
+    stats::measure(DEVICE_NOT_AVAILABLE_VECTOR, || {
+        if CURRENT.get().load(Ordering::Relaxed).is_null() {
+            panic!("fpu: FPU used outside of a thread at {:?}", stack_frame.instruction_pointer);
+        }
+        take_ownership();
+    });
+}
+
+#[allow(dead_code)]
+fn set_xmm0(value: u64) {
+    unsafe { asm!("movq xmm0, {}", in(reg) value, options(nomem, nostack)) };
+}
+
+#[allow(dead_code)]
+fn xmm0() -> u64 {
+    let value: u64;
+    unsafe { asm!("movq {}, xmm0", out(reg) value, options(nomem, nostack)) };
+    value
+}
+
+#[cfg(test)]
+fn check_sse_per_thread() {
+    set_xmm0(1);
+    let handle = super::spawn("fpu", || {
+        set_xmm0(2);
+        super::yield_now();
+        assert_eq!(xmm0(), 2);
+    });
+    super::yield_now();
+    assert_eq!(xmm0(), 1);
+    handle.join();
+    assert_eq!(xmm0(), 1);
+}
+
+#[test_case]
+fn sse_registers_are_per_thread() {
+    check_sse_per_thread();
+}
+
+#[test_case]
+fn eager_switching_never_traps() {
+    set_eager(true);
+    let before = stats::total(DEVICE_NOT_AVAILABLE_VECTOR).count;
+    check_sse_per_thread();
+    let traps = stats::total(DEVICE_NOT_AVAILABLE_VECTOR).count - before;
+    set_eager(false);
+    assert_eq!(traps, 0);
+}
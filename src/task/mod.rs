@@ -0,0 +1,198 @@
+/*
+ * Kernel threads and async tasks.
+ *
+ * `spawn` creates a thread with its own stack that is scheduled round-robin
+ * with all other threads and preempted by the timer interrupt. As a lighter
+ * alternative, futures can run cooperatively on an `executor::Executor`.
+ */
+mod context;
+pub mod executor;
+pub mod fpu;
+pub mod policy;
+pub mod scheduler;
+pub mod thread;
+
+use crate::time::{Duration, Instant};
+use alloc::boxed::Box;
+pub use policy::SchedPolicy;
+pub use scheduler::{current_id, exit, print_threads, set_policy};
+use thread::{Thread, ThreadId};
+
+/// Handle to wait for a thread. Dropping it detaches the thread.
+pub struct JoinHandle {
+    id: ThreadId,
+    joined: bool,
+}
+
+impl JoinHandle {
+    #[allow(dead_code)]
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    /// Wait for the thread to exit
+    pub fn join(mut self) {
+        scheduler::join(self.id);
+        self.joined = true;
+    }
+}
+
+impl Drop for JoinHandle {
+    fn drop(&mut self) {
+        if !self.joined {
+            scheduler::detach(self.id);
+        }
+    }
+}
+
+/// Start a kernel thread running `f` with the default policy
+pub fn spawn(name: &'static str, f: impl FnOnce() + Send + 'static) -> JoinHandle {
+    spawn_with_policy(name, SchedPolicy::default(), f)
+}
+
+/// Start a kernel thread running `f` in the given scheduling class
+pub fn spawn_with_policy(
+    name: &'static str,
+    policy: SchedPolicy,
+    f: impl FnOnce() + Send + 'static,
+) -> JoinHandle {
+    let thread = Thread::new(name, policy, Box::new(f), scheduler::thread_entry());
+    let id = scheduler::add_thread(thread);
+    JoinHandle { id, joined: false }
+}
+
+/// Give up the CPU to the next ready thread
+pub fn yield_now() {
+    scheduler::schedule();
+}
+
+/// Block the current thread for at least `duration`
+pub fn sleep(duration: Duration) {
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        scheduler::park_until(deadline);
+    }
+}
+
+/// Start scheduling. The caller becomes the "main" thread.
+pub fn init() {
+    scheduler::init();
+}
+
+#[test_case]
+fn spawn_and_join() {
+    use core::sync::atomic::{AtomicU64, Ordering};
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let handles: alloc::vec::Vec<JoinHandle> = (0..4)
+        .map(|_| {
+            spawn("test", || {
+                for _ in 0..10 {
+                    COUNTER.fetch_add(1, Ordering::SeqCst);
+                    yield_now();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join();
+    }
+    assert_eq!(COUNTER.load(Ordering::SeqCst), 40);
+}
+
+#[test_case]
+fn sleeping_thread_wakes_up() {
+    let start = Instant::now();
+    spawn("sleeper", || sleep(Duration::from_millis(20))).join();
+    assert!(start.elapsed() >= Duration::from_millis(20));
+}
+
+#[test_case]
+fn fifo_runs_before_priority() {
+    use alloc::vec::Vec;
+    use spin::Mutex;
+    static ORDER: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+
+    let (low, high) = x86_64::instructions::interrupts::without_interrupts(|| {
+        let low = spawn_with_policy("low", SchedPolicy::Priority { priority: 0 }, || {
+            ORDER.lock().push("low")
+        });
+        let high = spawn_with_policy("high", SchedPolicy::Fifo { priority: 10 }, || {
+            ORDER.lock().push("high")
+        });
+        (low, high)
+    });
+    low.join();
+    high.join();
+    assert_eq!(*ORDER.lock(), ["high", "low"]);
+}
+
+#[cfg(test)]
+fn spin_for(duration: Duration) {
+    let start = Instant::now();
+    while start.elapsed() < duration {
+        core::sync::atomic::spin_loop_hint();
+    }
+}
+
+#[test_case]
+fn aging_prevents_starvation() {
+    use core::sync::atomic::{AtomicBool, Ordering};
+    static LOW_RAN: AtomicBool = AtomicBool::new(false);
+
+    // the hog outranks low by two, aging makes up for that after 100ms
+    let start = Instant::now();
+    let hog = spawn_with_policy("hog", SchedPolicy::Priority { priority: 12 }, move || {
+        while !LOW_RAN.load(Ordering::SeqCst) && start.elapsed() < Duration::from_secs(2) {
+            core::sync::atomic::spin_loop_hint();
+        }
+    });
+    let low = spawn_with_policy("low", SchedPolicy::Priority { priority: 10 }, || {
+        LOW_RAN.store(true, Ordering::SeqCst)
+    });
+    low.join();
+    // without aging low would only run once the hog gave up
+    assert!(start.elapsed() < Duration::from_secs(1));
+    hog.join();
+}
+
+#[test_case]
+fn fair_share_follows_weight() {
+    use core::sync::atomic::{AtomicBool, Ordering};
+    static STOP: AtomicBool = AtomicBool::new(false);
+
+    let spinner = || {
+        while !STOP.load(Ordering::SeqCst) {
+            core::sync::atomic::spin_loop_hint();
+        }
+    };
+    let (light, heavy) = x86_64::instructions::interrupts::without_interrupts(|| {
+        let light = spawn_with_policy("light", SchedPolicy::Fair { weight: 1024 }, spinner);
+        let heavy = spawn_with_policy("heavy", SchedPolicy::Fair { weight: 3072 }, spinner);
+        (light, heavy)
+    });
+    // the priority class of this thread preempts both when the sleep is over
+    sleep(Duration::from_millis(300));
+    let light_nanos = scheduler::stats_of(light.id()).unwrap().cpu_nanos;
+    let heavy_nanos = scheduler::stats_of(heavy.id()).unwrap().cpu_nanos;
+    STOP.store(true, Ordering::SeqCst);
+    light.join();
+    heavy.join();
+    // 3:1 expected, give or take a time slice
+    assert!(light_nanos > 0);
+    assert!(heavy_nanos >= 2 * light_nanos && heavy_nanos <= 4 * light_nanos);
+}
+
+#[test_case]
+fn stats_track_switches_and_runtime() {
+    let before = scheduler::stats_of(current_id()).unwrap();
+    spin_for(Duration::from_millis(5));
+    spawn("switcher", || {
+        let stats = scheduler::stats_of(current_id()).unwrap();
+        assert!(stats.switches >= 1);
+    })
+    .join();
+    let after = scheduler::stats_of(current_id()).unwrap();
+    // switched back in after the join
+    assert!(after.switches > before.switches);
+    assert!(after.cpu_nanos >= before.cpu_nanos + 5_000_000);
+}
@@ -0,0 +1,110 @@
+/*
+ * Scheduling classes.
+ *
+ * Ready threads are picked class by class:
+ *   Fifo      real-time threads, e.g. for interrupt handling. The highest
+ *             priority runs until it blocks or yields, there is no time slice.
+ *   Priority  static priority plus an aging bonus that grows while the thread
+ *             waits, so low priorities cannot starve. Time sliced.
+ *   Fair      threads share the CPU in proportion to their weight: the one
+ *             with the least weighted CPU time (vruntime) runs next.
+ */
+use core::cmp::Ordering;
+use core::fmt;
+
+pub const MAX_PRIORITY: u8 = 39;
+pub const DEFAULT_PRIORITY: u8 = 20;
+/// Waiting this long raises the effective priority by one
+pub const AGING_INTERVAL_NANOS: u64 = 50_000_000;
+/// Weight for which vruntime advances at the speed of real time
+pub const DEFAULT_WEIGHT: u32 = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedPolicy {
+    Fifo { priority: u8 },
+    Priority { priority: u8 },
+    Fair { weight: u32 },
+}
+
+impl Default for SchedPolicy {
+    fn default() -> Self {
+        SchedPolicy::Priority {
+            priority: DEFAULT_PRIORITY,
+        }
+    }
+}
+
+impl SchedPolicy {
+    /// Lower classes run first
+    fn class(&self) -> u8 {
+        match self {
+            SchedPolicy::Fifo { .. } => 0,
+            SchedPolicy::Priority { .. } => 1,
+            SchedPolicy::Fair { .. } => 2,
+        }
+    }
+
+    /// Whether a thread with this policy is more urgent than one with `other`,
+    /// ignoring aging and vruntime. Used for priority inheritance.
+    pub fn outranks(&self, other: &SchedPolicy) -> bool {
+        match (self, other) {
+            _ if self.class() != other.class() => self.class() < other.class(),
+            (SchedPolicy::Fifo { priority: a }, SchedPolicy::Fifo { priority: b })
+            | (SchedPolicy::Priority { priority: a }, SchedPolicy::Priority { priority: b }) => a > b,
+            _ => false,
+        }
+    }
+
+    pub fn is_time_sliced(&self) -> bool {
+        !matches!(self, SchedPolicy::Fifo { .. })
+    }
+
+    /// vruntime advance for `nanos` of CPU time
+    pub fn weighted(&self, nanos: u64) -> u64 {
+        match self {
+            SchedPolicy::Fair { weight } => nanos * DEFAULT_WEIGHT as u64 / (*weight).max(1) as u64,
+            _ => nanos,
+        }
+    }
+}
+
+impl fmt::Display for SchedPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SchedPolicy::Fifo { priority } => write!(f, "fifo/{}", priority),
+            SchedPolicy::Priority { priority } => write!(f, "prio/{}", priority),
+            SchedPolicy::Fair { weight } => write!(f, "fair/{}", weight),
+        }
+    }
+}
+
+/// What the scheduler needs to know about a ready thread to compare it
+#[derive(Debug, Clone, Copy)]
+pub struct Candidate {
+    pub policy: SchedPolicy,
+    pub waiting_nanos: u64,
+    pub vruntime: u64,
+}
+
+impl Candidate {
+    fn effective_priority(&self) -> u64 {
+        match self.policy {
+            SchedPolicy::Fifo { priority } => priority as u64,
+            SchedPolicy::Priority { priority } => priority as u64 + self.waiting_nanos / AGING_INTERVAL_NANOS,
+            SchedPolicy::Fair { .. } => 0,
+        }
+    }
+
+    /// Ordering by urgency, `Greater` means `self` should run first. Equal
+    /// candidates keep their queue order.
+    pub fn compare(&self, other: &Candidate) -> Ordering {
+        let by_class = other.policy.class().cmp(&self.policy.class());
+        if by_class != Ordering::Equal {
+            return by_class;
+        }
+        match self.policy {
+            SchedPolicy::Fair { .. } => other.vruntime.cmp(&self.vruntime),
+            _ => self.effective_priority().cmp(&other.effective_priority()),
+        }
+    }
+}
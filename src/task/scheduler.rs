@@ -0,0 +1,657 @@
+/*
+ * Scheduler for kernel threads.
+ *
+ * Ready threads wait in the run queue, the next one is picked according to
+ * the scheduling classes in the policy module; equal threads run round-robin.
+ * The timer interrupt counts down the time slice of the running thread; once
+ * it is used up the thread is preempted on the way out of the interrupt and
+ * put at the back of the queue. A wakeup preempts the running thread if the
+ * woken one is more urgent. The idle thread runs only when the queue is empty.
+ *
+ * Every CPU that runs threads has its own scheduler with its own threads,
+ * threads do not move between CPUs. So far that is only the BSP, the APs are
+ * parked without a scheduler (see smp). A ThreadId tells the CPU of the thread. The scheduler state of
+ * a CPU is protected by its SCHEDULER lock, which is only taken with
+ * interrupts disabled. The lock is released before the actual context switch,
+ * interrupts stay disabled until the next thread re-enables them.
+ */
+use super::context::switch_context;
+use super::fpu;
+use super::policy::{Candidate, SchedPolicy};
+use super::thread::{Thread, ThreadId, ThreadState, ThreadStats};
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use crate::smp::ipi::{self, RESCHEDULE_VECTOR};
+use crate::smp::percpu::{self, NO_THREAD};
+use crate::time::{clocksource, timer_wheel, Instant};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Timer ticks a thread may run before it is preempted
+pub const TIME_SLICE_TICKS: u32 = 10;
+/// Longest chain of mutex owners that priority inheritance follows
+const MAX_PI_CHAIN: usize = 16;
+
+pub struct Scheduler {
+    cpu: usize,
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    run_queue: VecDeque<ThreadId>,
+    current: ThreadId,
+    idle: ThreadId,
+    slice_left: u32,
+    // smallest vruntime of the fair class, new fair threads start there
+    min_vruntime: u64,
+}
+
+crate::percpu! { static SCHEDULER: Mutex<Option<Scheduler>> = Mutex::new(None); }
+crate::percpu! { static NEED_RESCHED: AtomicBool = AtomicBool::new(false); }
+
+/// Run `f` on the scheduler of this CPU with interrupts disabled
+pub(super) fn with_scheduler<R>(f: impl FnOnce(&mut Scheduler) -> R) -> R {
+    interrupts::without_interrupts(|| {
+        let mut guard = SCHEDULER.get().lock();
+        f(guard.as_mut().expect("scheduler not initialized"))
+    })
+}
+
+/// Run `f` on the scheduler owning thread `id`, None if that CPU has none
+fn with_scheduler_of<R>(id: ThreadId, f: impl FnOnce(&mut Scheduler) -> R) -> Option<R> {
+    interrupts::without_interrupts(|| SCHEDULER.on(id.cpu()).lock().as_mut().map(f))
+}
+
+/// Turn the running flow of control into the boot thread of this CPU and
+/// create its idle thread. Needs the heap.
+pub fn init() {
+    let cpu = percpu::cpu_id();
+    let boot = Thread::boot_thread();
+    let idle = Thread::new("idle", SchedPolicy::default(), Box::new(idle_loop), thread_start);
+    let (boot_id, idle_id) = (boot.id, idle.id);
+    // the area is on the heap, so it stays put when the thread moves into the map
+    fpu::set_current(&boot.fpu);
+    let mut threads = BTreeMap::new();
+    threads.insert(boot_id, boot);
+    threads.insert(idle_id, idle);
+    interrupts::without_interrupts(|| {
+        *SCHEDULER.get().lock() = Some(Scheduler {
+            cpu,
+            threads,
+            run_queue: VecDeque::with_capacity(2),
+            current: boot_id,
+            idle: idle_id,
+            slice_left: TIME_SLICE_TICKS,
+            min_vruntime: 0,
+        });
+        percpu::area().current_thread.store(boot_id.as_u64(), Ordering::Relaxed);
+    });
+}
+
+fn idle_loop() {
+    loop {
+        crate::watchdog::pet();
+        // enable interrupts and halt atomically, so no wakeup is missed
+        interrupts::enable_and_hlt();
+        interrupts::disable();
+        if with_scheduler(|s| !s.run_queue.is_empty()) {
+            schedule();
+        }
+        interrupts::enable();
+    }
+}
+
+/// Entry point used for new threads
+pub(super) fn thread_entry() -> extern "C" fn() -> ! {
+    thread_start
+}
+
+/// First code run by every new thread, reached through `switch_context`
+extern "C" fn thread_start() -> ! {
+    finish_switch();
+    let entry = with_scheduler(|s| {
+        let current = s.current;
+        s.threads.get_mut(&current).unwrap().entry.take()
+    });
+    interrupts::enable();
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit();
+}
+
+impl Scheduler {
+    fn thread(&mut self, id: ThreadId) -> Option<&mut Thread> {
+        self.threads.get_mut(&id).map(|t| &mut **t)
+    }
+
+    fn candidate(&self, id: ThreadId, now: u64) -> Candidate {
+        let thread = &self.threads[&id];
+        Candidate {
+            policy: thread.policy,
+            waiting_nanos: now.saturating_sub(thread.ready_since),
+            vruntime: thread.vruntime,
+        }
+    }
+
+    /// Put a ready thread at the back of the run queue
+    fn enqueue(&mut self, id: ThreadId, now: u64) {
+        let min_vruntime = self.min_vruntime;
+        let thread = self.thread(id).unwrap();
+        thread.ready_since = now;
+        // a thread that slept must not catch up on all the CPU time it missed
+        thread.vruntime = thread.vruntime.max(min_vruntime);
+        self.run_queue.push_back(id);
+    }
+
+    /// Ask for a reschedule if the ready thread `id` should run instead of
+    /// the current one
+    fn check_preempt(&mut self, id: ThreadId, now: u64) {
+        let current = self.current;
+        let preempts = current == self.idle || {
+            let new = self.candidate(id, now);
+            let running = self.candidate(current, now);
+            let both_fair = matches!((new.policy, running.policy), (SchedPolicy::Fair { .. }, SchedPolicy::Fair { .. }));
+            // fair threads wait for the end of the slice
+            !both_fair && new.compare(&running) == core::cmp::Ordering::Greater
+        };
+        if preempts {
+            NEED_RESCHED.on(self.cpu).store(true, Ordering::Relaxed);
+            if self.cpu != percpu::cpu_id() {
+                ipi::send(self.cpu, RESCHEDULE_VECTOR);
+            }
+        }
+    }
+
+    /// Make a blocked thread runnable
+    fn make_ready(&mut self, id: ThreadId) {
+        let now = clocksource::now();
+        match self.thread(id) {
+            Some(thread) if thread.state == ThreadState::Blocked => {
+                thread.state = ThreadState::Ready;
+                thread.woken_at = Some(now);
+                thread.stats.wakeups += 1;
+            }
+            _ => return,
+        }
+        self.enqueue(id, now);
+        self.check_preempt(id, now);
+    }
+
+    /// Recompute the effective policy of `id` from its own and that of the
+    /// threads blocked on its mutexes, then go on with the owner `id` is
+    /// blocked on, if any
+    fn update_inherited(&mut self, mut id: ThreadId) {
+        let now = clocksource::now();
+        for _ in 0..MAX_PI_CHAIN {
+            let thread = match self.threads.get(&id) {
+                Some(thread) => thread,
+                None => return,
+            };
+            let mut policy = thread.base_policy;
+            for waiter in thread.pi_waiters.iter().filter_map(|w| self.threads.get(w)) {
+                if waiter.policy.outranks(&policy) {
+                    policy = waiter.policy;
+                }
+            }
+            let next = thread.waiting_for.map(|(owner, _)| owner);
+            let thread = self.thread(id).unwrap();
+            if thread.policy == policy {
+                return;
+            }
+            let raised = policy.outranks(&thread.policy);
+            thread.policy = policy;
+            if raised && thread.state == ThreadState::Ready {
+                self.check_preempt(id, now);
+            } else if !raised && id == self.current {
+                // a thread we held back may be more urgent now
+                NEED_RESCHED.on(self.cpu).store(true, Ordering::Relaxed);
+            }
+            match next {
+                Some(owner) => id = owner,
+                None => return,
+            }
+        }
+    }
+
+    /// Stop lending priority to the owner `id` is blocked on
+    fn pi_detach(&mut self, id: ThreadId) {
+        let owner = match self.thread(id).and_then(|t| t.waiting_for.take()) {
+            Some((owner, _)) => owner,
+            None => return,
+        };
+        if let Some(owner_thread) = self.thread(owner) {
+            owner_thread.pi_waiters.retain(|&w| w != id);
+        }
+        self.update_inherited(owner);
+    }
+
+    /// Make `waiter` lend its priority to `owner` of mutex `lock`
+    fn pi_attach(&mut self, waiter: ThreadId, owner: ThreadId, lock: usize) {
+        if waiter == owner || !self.threads.contains_key(&owner) {
+            return;
+        }
+        if self.threads.get(&waiter).and_then(|t| t.waiting_for) == Some((owner, lock)) {
+            return;
+        }
+        self.pi_detach(waiter);
+        match self.thread(waiter) {
+            Some(thread) => thread.waiting_for = Some((owner, lock)),
+            None => return,
+        }
+        self.thread(owner).unwrap().pi_waiters.push(waiter);
+        self.update_inherited(owner);
+    }
+
+    /// Remove the most urgent thread from the run queue
+    fn take_next(&mut self, now: u64) -> Option<ThreadId> {
+        let mut best: Option<(usize, Candidate)> = None;
+        for (index, &id) in self.run_queue.iter().enumerate() {
+            let candidate = self.candidate(id, now);
+            match best {
+                Some((_, ref best_candidate))
+                    if candidate.compare(best_candidate) != core::cmp::Ordering::Greater => {}
+                _ => best = Some((index, candidate)),
+            }
+        }
+        best.and_then(|(index, _)| self.run_queue.remove(index))
+    }
+
+    /// Pick the next thread and return the stack pointers to switch with,
+    /// or None if the current thread keeps running
+    fn pick_next(&mut self) -> Option<(*mut u64, u64)> {
+        let now = clocksource::now();
+        let current = self.current;
+        let idle = self.idle;
+        let current_thread = self.thread(current).unwrap();
+        let ran = now.saturating_sub(current_thread.last_run);
+        current_thread.stats.cpu_nanos += ran;
+        current_thread.vruntime += current_thread.policy.weighted(ran);
+        current_thread.last_run = now;
+        if current_thread.state == ThreadState::Running {
+            current_thread.state = ThreadState::Ready;
+            if current != idle {
+                self.enqueue(current, now);
+            }
+        }
+
+        let next = self.take_next(now).unwrap_or(idle);
+        self.slice_left = TIME_SLICE_TICKS;
+        let next_thread = self.thread(next).unwrap();
+        next_thread.state = ThreadState::Running;
+        next_thread.last_run = now;
+        if let Some(woken_at) = next_thread.woken_at.take() {
+            let latency = now.saturating_sub(woken_at);
+            next_thread.stats.total_wakeup_latency += latency;
+            next_thread.stats.max_wakeup_latency = next_thread.stats.max_wakeup_latency.max(latency);
+        }
+        if let SchedPolicy::Fair { .. } = next_thread.policy {
+            let vruntime = next_thread.vruntime;
+            self.min_vruntime = self.min_vruntime.max(vruntime);
+        }
+        if next == current {
+            return None;
+        }
+        self.thread(next).unwrap().stats.switches += 1;
+        self.current = next;
+        percpu::area().current_thread.store(next.as_u64(), Ordering::Relaxed);
+        fpu::switch_to(&self.thread(next).unwrap().fpu);
+        let new_rsp = self.thread(next).unwrap().rsp;
+        let old_rsp = &mut self.thread(current).unwrap().rsp as *mut u64;
+        Some((old_rsp, new_rsp))
+    }
+}
+
+/// Switch to the next ready thread, if any. The current thread goes back to
+/// the run queue if it is still running, otherwise it waits to be woken.
+pub fn schedule() {
+    interrupts::without_interrupts(|| {
+        crate::watchdog::pet();
+        NEED_RESCHED.get().store(false, Ordering::Relaxed);
+        let switch = SCHEDULER.get().lock().as_mut().and_then(|s| s.pick_next());
+        if let Some((old_rsp, new_rsp)) = switch {
+            unsafe {
+                switch_context(old_rsp, new_rsp);
+            }
+            finish_switch();
+        }
+    });
+}
+
+/// Cleanup after a switch, run by the thread that was switched to: free the
+/// stacks of exited threads nobody will join. This runs on the way out of
+/// interrupts, so it frees them one by one instead of collecting them.
+fn finish_switch() {
+    loop {
+        let dead = with_scheduler(|s| {
+            let current = s.current;
+            let id = s
+                .threads
+                .values()
+                .find(|t| t.state == ThreadState::Exited && t.detached && t.id != current)
+                .map(|t| t.id)?;
+            s.threads.remove(&id)
+        });
+        match dead {
+            Some(thread) => drop(thread),
+            None => return,
+        }
+    }
+}
+
+/// Called on every timer tick
+pub fn tick() {
+    if let Some(mut guard) = SCHEDULER.get().try_lock() {
+        if let Some(scheduler) = guard.as_mut() {
+            let current = scheduler.current;
+            if scheduler.threads[&current].policy.is_time_sliced() {
+                scheduler.slice_left = scheduler.slice_left.saturating_sub(1);
+            }
+            if scheduler.slice_left == 0 || current == scheduler.idle {
+                NEED_RESCHED.get().store(true, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/*
+ * Preemption is off on this CPU while the count is non-zero, e.g. while a
+ * sync::SpinLock is held.
+ */
+pub fn preempt_disable() {
+    percpu::area().preempt_count.fetch_add(1, Ordering::Acquire);
+}
+
+pub fn preempt_enable() {
+    let previous = percpu::area().preempt_count.fetch_sub(1, Ordering::Release);
+    debug_assert!(previous > 0, "unbalanced preempt_enable");
+}
+
+pub fn preempt_count() -> usize {
+    percpu::area().preempt_count.load(Ordering::Relaxed) as usize
+}
+
+/// Called at the end of an interrupt, switches away if the time slice is over.
+/// Only `preempt_count` holds it off, so a plain spin::Mutex that is not a
+/// sync::SpinLock must be held with interrupts disabled.
+pub fn preempt_if_needed() {
+    if NEED_RESCHED.get().load(Ordering::Relaxed) && preempt_count() == 0 {
+        schedule();
+    }
+}
+
+pub fn current_id() -> ThreadId {
+    try_current_id().expect("scheduler not initialized")
+}
+
+pub(super) fn add_thread(thread: Box<Thread>) -> ThreadId {
+    with_scheduler(|s| {
+        let id = thread.id;
+        let now = clocksource::now();
+        s.threads.insert(id, thread);
+        // every thread fits into the run queue, so wakeups and preemption
+        // never allocate in an interrupt
+        let threads = s.threads.len();
+        s.run_queue.reserve(threads.saturating_sub(s.run_queue.len()));
+        s.enqueue(id, now);
+        s.check_preempt(id, now);
+        id
+    })
+}
+
+/// Block the current thread until `unpark` is called for it. Returns at once
+/// if an unpark already arrived since the last park.
+pub fn park() {
+    interrupts::without_interrupts(|| {
+        let must_block = with_scheduler(|s| {
+            let current = s.current;
+            let thread = s.thread(current).unwrap();
+            if thread.unpark_token {
+                thread.unpark_token = false;
+                false
+            } else {
+                thread.state = ThreadState::Blocked;
+                true
+            }
+        });
+        if must_block {
+            schedule();
+        }
+    });
+}
+
+/// Park, but return at the latest at `deadline`
+pub fn park_until(deadline: Instant) {
+    if Instant::now() >= deadline {
+        return;
+    }
+    let id = current_id();
+    let timer = timer_wheel::add_timer(deadline, move || unpark(id));
+    park();
+    timer_wheel::del_timer(timer);
+}
+
+/// Wake a parked thread, or make its next park return immediately
+pub fn unpark(id: ThreadId) {
+    with_scheduler_of(id, |s| match s.thread(id).map(|t| t.state) {
+        Some(ThreadState::Blocked) => s.make_ready(id),
+        Some(ThreadState::Exited) | None => {}
+        Some(_) => s.thread(id).unwrap().unpark_token = true,
+    });
+}
+
+/// Terminate the current thread and wake the threads joining it
+pub fn exit() -> ! {
+    interrupts::disable();
+    let joiners = with_scheduler(|s| {
+        let current = s.current;
+        s.thread(current).unwrap().state = ThreadState::Exited;
+        core::mem::replace(&mut s.thread(current).unwrap().joiners, Vec::new())
+    });
+    // the joiners may live on other CPUs, wake them without our lock held
+    for joiner in joiners {
+        unpark(joiner);
+    }
+    schedule();
+    unreachable!("exited thread was scheduled again");
+}
+
+/// Wait for thread `id` to exit and free it
+pub fn join(id: ThreadId) {
+    let current = current_id();
+    loop {
+        let exited = with_scheduler_of(id, |s| match s.thread(id) {
+            Some(thread) if thread.state == ThreadState::Exited => {
+                // still on its way out of exit() on its CPU: finish_switch
+                // there frees it once it switched away from its stack
+                thread.detached = true;
+                if s.current != id {
+                    s.threads.remove(&id);
+                }
+                true
+            }
+            Some(thread) => {
+                if !thread.joiners.contains(&current) {
+                    thread.joiners.push(current);
+                }
+                false
+            }
+            None => true,
+        });
+        if exited.unwrap_or(true) {
+            return;
+        }
+        park();
+    }
+}
+
+/// Let a thread's resources be freed as soon as it exits
+pub fn detach(id: ThreadId) {
+    with_scheduler_of(id, |s| {
+        if let Some(thread) = s.thread(id) {
+            thread.detached = true;
+        }
+    });
+}
+
+/// Change the scheduling policy of a thread
+pub fn set_policy(id: ThreadId, policy: SchedPolicy) {
+    with_scheduler_of(id, |s| {
+        if let Some(thread) = s.thread(id) {
+            thread.base_policy = policy;
+            thread.policy = policy;
+        }
+        // an inherited priority may still be higher
+        s.update_inherited(id);
+        if s.run_queue.contains(&id) {
+            s.check_preempt(id, clocksource::now());
+        }
+    });
+}
+
+/// Print the threads and their CPU accounting, like `ps`
+pub fn print_threads() {
+    struct Row {
+        cpu: usize,
+        id: ThreadId,
+        name: &'static str,
+        state: ThreadState,
+        policy: SchedPolicy,
+        stats: ThreadStats,
+    }
+
+    let mut rows: Vec<Row> = Vec::new();
+    for cpu in 0..crate::smp::cpu_count() {
+        interrupts::without_interrupts(|| {
+            let guard = SCHEDULER.on(cpu).lock();
+            let s = match guard.as_ref() {
+                Some(s) => s,
+                None => return,
+            };
+            let now = clocksource::now();
+            rows.extend(s.threads.values().map(|t| {
+                let mut stats = t.stats;
+                if t.id == s.current {
+                    stats.cpu_nanos += now.saturating_sub(t.last_run);
+                }
+                Row {
+                    cpu,
+                    id: t.id,
+                    name: t.name,
+                    state: t.state,
+                    policy: t.policy,
+                    stats,
+                }
+            }));
+        });
+    }
+    let total: u64 = rows.iter().map(|row| row.stats.cpu_nanos).sum::<u64>().max(1);
+
+    println!(
+        "{:>3} {:>4} {:<12} {:<8} {:<9} {:>9} {:>5} {:>8} {:>8} {:>10} {:>10}",
+        "CPU", "TID", "NAME", "STATE", "POLICY", "CPU(ms)", "%CPU", "SWITCH", "WAKEUP", "AVGLAT(us)", "MAXLAT(us)"
+    );
+    for row in rows.iter() {
+        let policy = alloc::format!("{}", row.policy);
+        println!(
+            "{:>3} {:>4} {:<12} {:<8} {:<9} {:>9} {:>5} {:>8} {:>8} {:>10} {:>10}",
+            row.cpu,
+            row.id,
+            row.name,
+            alloc::format!("{:?}", row.state),
+            policy,
+            row.stats.cpu_nanos / 1_000_000,
+            row.stats.cpu_nanos * 100 / total,
+            row.stats.switches,
+            row.stats.wakeups,
+            row.stats.avg_wakeup_latency() / 1000,
+            row.stats.max_wakeup_latency / 1000,
+        );
+    }
+}
+
+/// Id of the running thread, None before the scheduler of this CPU is
+/// initialized
+pub fn try_current_id() -> Option<ThreadId> {
+    match percpu::area().current_thread.load(Ordering::Relaxed) {
+        NO_THREAD => None,
+        id => Some(ThreadId::from_u64(id)),
+    }
+}
+
+/// Name of the running thread, None if the scheduler lock is busy. Safe to
+/// call from any context.
+pub fn try_current_name() -> Option<&'static str> {
+    let id = try_current_id()?;
+    let guard = SCHEDULER.get().try_lock()?;
+    guard.as_ref()?.threads.get(&id).map(|t| t.name)
+}
+
+/// CPU accounting of a thread, including the time of its current run
+#[allow(dead_code)]
+pub fn stats_of(id: ThreadId) -> Option<ThreadStats> {
+    with_scheduler_of(id, |s| {
+        let thread = s.threads.get(&id)?;
+        let mut stats = thread.stats;
+        if id == s.current {
+            stats.cpu_nanos += clocksource::now().saturating_sub(thread.last_run);
+        }
+        Some(stats)
+    })
+    .flatten()
+}
+
+/// Effective policy of a thread, including inherited priority
+#[allow(dead_code)]
+pub fn policy_of(id: ThreadId) -> Option<SchedPolicy> {
+    with_scheduler_of(id, |s| s.threads.get(&id).map(|t| t.policy)).flatten()
+}
+
+/*
+ * Priority inheritance hooks for sync::Mutex. A mutex is identified by its
+ * address. A thread about to block on a mutex lends its priority to the owner,
+ * and transitively to the owner's owner if that one is blocked as well.
+ * Priority is only lent between threads of the same CPU.
+ */
+
+/// The current thread is going to block on `lock`, held by `owner`
+pub fn pi_block_on(lock: usize, owner: ThreadId) {
+    with_scheduler(|s| {
+        let current = s.current;
+        s.pi_attach(current, owner, lock);
+    });
+}
+
+/// The current thread stopped waiting for a mutex, it got it or gave up
+pub fn pi_unblock() {
+    with_scheduler(|s| {
+        let current = s.current;
+        s.pi_detach(current);
+    });
+}
+
+/// The current thread released `lock`, drop the priority lent for it
+pub fn pi_release(lock: usize) {
+    with_scheduler(|s| {
+        let current = s.current;
+        let waiters = core::mem::replace(&mut s.thread(current).unwrap().pi_waiters, Vec::new());
+        let (released, kept): (Vec<ThreadId>, Vec<ThreadId>) = waiters
+            .into_iter()
+            .partition(|w| s.threads.get(w).and_then(|t| t.waiting_for).map(|(_, l)| l) == Some(lock));
+        for waiter in released {
+            s.thread(waiter).unwrap().waiting_for = None;
+        }
+        s.thread(current).unwrap().pi_waiters = kept;
+        s.update_inherited(current);
+    });
+}
+
+/// The current thread acquired `lock`, the remaining `waiters` lend it their
+/// priority from now on
+pub fn pi_take_over(lock: usize, waiters: &[ThreadId]) {
+    with_scheduler(|s| {
+        let current = s.current;
+        for &waiter in waiters {
+            s.pi_attach(waiter, current, lock);
+        }
+    });
+}
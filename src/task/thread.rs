@@ -0,0 +1,192 @@
+use super::context;
+use super::fpu::FpuState;
+use super::policy::SchedPolicy;
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Stack size of kernel threads
+pub const STACK_SIZE: usize = 16 * 1024;
+
+/// The CPU a thread runs on is kept in the top bits of its id
+const CPU_SHIFT: u32 = 48;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    /// A new id for a thread of the current CPU
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        let cpu = crate::smp::percpu::cpu_id() as u64;
+        ThreadId(cpu << CPU_SHIFT | NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn cpu(&self) -> usize {
+        (self.0 >> CPU_SHIFT) as usize
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+
+    /// Inverse of `as_u64`, for ids kept in atomics
+    pub fn from_u64(id: u64) -> Self {
+        ThreadId(id)
+    }
+}
+
+impl fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let number = self.0 & ((1 << CPU_SHIFT) - 1);
+        fmt::Display::fmt(&number, f)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Ready,
+    Running,
+    Blocked,
+    Exited,
+}
+
+/// CPU accounting of a thread
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ThreadStats {
+    pub cpu_nanos: u64,
+    /// How often the thread was switched in
+    pub switches: u64,
+    /// How often the thread was woken after blocking
+    pub wakeups: u64,
+    /// Time from wakeup until the thread actually ran, summed up
+    pub total_wakeup_latency: u64,
+    pub max_wakeup_latency: u64,
+}
+
+impl ThreadStats {
+    pub fn avg_wakeup_latency(&self) -> u64 {
+        if self.wakeups == 0 {
+            0
+        } else {
+            self.total_wakeup_latency / self.wakeups
+        }
+    }
+}
+
+pub struct Thread {
+    pub(super) id: ThreadId,
+    pub(super) name: &'static str,
+    pub(super) state: ThreadState,
+    // saved stack pointer while the thread is switched out
+    pub(super) rsp: u64,
+    // None for the boot thread, which runs on the bootloader's stack
+    #[allow(dead_code)]
+    pub(super) stack: Option<Box<[u64]>>,
+    pub(super) entry: Option<Box<dyn FnOnce() + Send>>,
+    // an unpark() arrived while the thread was not parked
+    pub(super) unpark_token: bool,
+    pub(super) joiners: Vec<ThreadId>,
+    pub(super) detached: bool,
+    // effective policy, may be boosted by priority inheritance
+    pub(super) policy: SchedPolicy,
+    // policy set by spawn or set_policy
+    pub(super) base_policy: SchedPolicy,
+    // threads blocked on mutexes this thread holds
+    pub(super) pi_waiters: Vec<ThreadId>,
+    // owner and key of the mutex this thread is blocked on
+    pub(super) waiting_for: Option<(ThreadId, usize)>,
+    pub(super) stats: ThreadStats,
+    // weighted CPU time for the fair class
+    pub(super) vruntime: u64,
+    // clocksource time the thread was queued, woken or switched in at
+    pub(super) ready_since: u64,
+    pub(super) woken_at: Option<u64>,
+    pub(super) last_run: u64,
+    // FPU and SSE registers while another thread owns them
+    pub(super) fpu: FpuState,
+}
+
+impl Thread {
+    /// The thread that is already running when the scheduler starts
+    pub(super) fn boot_thread() -> Box<Thread> {
+        Box::new(Thread {
+            id: ThreadId::new(),
+            name: "main",
+            state: ThreadState::Running,
+            rsp: 0,
+            stack: None,
+            entry: None,
+            unpark_token: false,
+            joiners: Vec::new(),
+            detached: true,
+            policy: SchedPolicy::default(),
+            base_policy: SchedPolicy::default(),
+            pi_waiters: Vec::new(),
+            waiting_for: None,
+            stats: ThreadStats::default(),
+            vruntime: 0,
+            ready_since: 0,
+            woken_at: None,
+            last_run: 0,
+            fpu: FpuState::new(),
+        })
+    }
+
+    pub(super) fn new(
+        name: &'static str,
+        policy: SchedPolicy,
+        entry: Box<dyn FnOnce() + Send>,
+        start: extern "C" fn() -> !,
+    ) -> Box<Thread> {
+        let mut stack = alloc::vec![0u64; STACK_SIZE / 8].into_boxed_slice();
+        let rsp = context::init_stack(&mut stack, start);
+        Box::new(Thread {
+            id: ThreadId::new(),
+            name,
+            state: ThreadState::Ready,
+            rsp,
+            stack: Some(stack),
+            entry: Some(entry),
+            unpark_token: false,
+            joiners: Vec::new(),
+            detached: false,
+            policy,
+            base_policy: policy,
+            pi_waiters: Vec::new(),
+            waiting_for: None,
+            stats: ThreadStats::default(),
+            vruntime: 0,
+            ready_since: 0,
+            woken_at: None,
+            last_run: 0,
+            fpu: FpuState::new(),
+        })
+    }
+
+    #[allow(dead_code)]
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    #[allow(dead_code)]
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    #[allow(dead_code)]
+    pub fn state(&self) -> ThreadState {
+        self.state
+    }
+
+    #[allow(dead_code)]
+    pub fn policy(&self) -> SchedPolicy {
+        self.policy
+    }
+
+    #[allow(dead_code)]
+    pub fn stats(&self) -> ThreadStats {
+        self.stats
+    }
+}
@@ -0,0 +1,99 @@
+/*
+ * High resolution clocksource.
+ *
+ * `now()` returns nanoseconds since the clocksource was initialized, read from
+ * the best clock available:
+ *     1. the invariant TSC, calibrated at boot
+ *     2. the HPET main counter
+ *     3. the PIT tick counter (only tick resolution)
+ */
+use super::tsc;
+use crate::drivers::hpet;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use x86_64::structures::paging::{FrameAllocator, Mapper, Size4KiB};
+use x86_64::VirtAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Clocksource {
+    Pit = 0,
+    Hpet = 1,
+    Tsc = 2,
+}
+
+static SOURCE: AtomicU8 = AtomicU8::new(Clocksource::Pit as u8);
+// raw reading of the selected source at init time
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+pub fn current() -> Clocksource {
+    match SOURCE.load(Ordering::Relaxed) {
+        2 => Clocksource::Tsc,
+        1 => Clocksource::Hpet,
+        _ => Clocksource::Pit,
+    }
+}
+
+fn raw_nanos(source: Clocksource) -> u64 {
+    match source {
+        Clocksource::Tsc => tsc::cycles_to_nanos(tsc::rdtsc()),
+        Clocksource::Hpet => hpet::nanos(),
+        Clocksource::Pit => super::Instant::now().as_nanos(),
+    }
+}
+
+/// Look for an HPET, calibrate the TSC and select the best clocksource
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    physical_memory_offset: VirtAddr,
+) {
+    let has_hpet = hpet::init(mapper, frame_allocator, physical_memory_offset);
+    tsc::calibrate();
+
+    let source = if tsc::is_invariant() {
+        Clocksource::Tsc
+    } else if has_hpet {
+        Clocksource::Hpet
+    } else {
+        Clocksource::Pit
+    };
+    BASE.store(raw_nanos(source), Ordering::Relaxed);
+    SOURCE.store(source as u8, Ordering::Relaxed);
+    serial_println!("clocksource: using the {:?}", source);
+}
+
+/// Nanoseconds since the clocksource was initialized
+pub fn now() -> u64 {
+    raw_nanos(current()).saturating_sub(BASE.load(Ordering::Relaxed))
+}
+
+#[test_case]
+fn clocksource_is_monotonic() {
+    let first = now();
+    let second = now();
+    assert!(second >= first);
+}
+
+#[test_case]
+fn clocksource_agrees_with_pit() {
+    use super::{ticks, TIMER_HZ};
+    use crate::task::scheduler;
+    const TICKS: u64 = 50;
+
+    // measure from one tick edge to another, without being switched out
+    scheduler::preempt_disable();
+    let edge = ticks();
+    while ticks() == edge {
+        core::sync::atomic::spin_loop_hint();
+    }
+    let (start_ticks, start) = (ticks(), now());
+    while ticks() < start_ticks + TICKS {
+        core::sync::atomic::spin_loop_hint();
+    }
+    let elapsed = now() - start;
+    scheduler::preempt_enable();
+
+    let expected = TICKS * 1_000_000_000 / TIMER_HZ as u64;
+    // a tick of jitter on either edge, plus some for emulated timers
+    assert!(elapsed > expected * 4 / 5 && elapsed < expected * 6 / 5, "{} ns for {} ns", elapsed, expected);
+}
@@ -0,0 +1,135 @@
+/*
+ * Monotonic kernel clock.
+ *
+ * The clock counts PIT input cycles since boot: every tick adds the divisor
+ * in effect when it fired. The nanosecond value is derived from the cycles,
+ * so it does not drift even if TIMER_HZ is not a divisor of the PIT clock, and
+ * stays monotonic when the PIT frequency is changed.
+ */
+pub mod clocksource;
+pub mod tsc;
+pub mod timer_wheel;
+pub mod wall_clock;
+
+use crate::drivers::pit;
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicU64, Ordering};
+pub use core::time::Duration;
+
+/// Rate of the timer interrupt
+pub const TIMER_HZ: u32 = 1000;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static PIT_CYCLES: AtomicU64 = AtomicU64::new(0);
+
+pub use wall_clock::wall_clock_now;
+
+/// Program the PIT to TIMER_HZ and read the wall-clock time from the RTC
+pub fn init() {
+    let hz = pit::set_frequency(TIMER_HZ);
+    serial_println!("time: PIT running at {} Hz", hz);
+    wall_clock::init();
+}
+
+/// Called by the timer interrupt handler on every tick
+pub fn tick() {
+    PIT_CYCLES.fetch_add(pit::divisor() as u64, Ordering::Relaxed);
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Number of timer interrupts since boot
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Nanoseconds since the timer was started
+fn clock_nanos() -> u64 {
+    let cycles = PIT_CYCLES.load(Ordering::Relaxed);
+    (cycles as u128 * 1_000_000_000 / pit::PIT_FREQUENCY as u128) as u64
+}
+
+/// Convert a number of ticks at the current PIT rate to nanoseconds
+fn ticks_to_nanos(ticks: u64) -> u64 {
+    (ticks as u128 * pit::divisor() as u128 * 1_000_000_000 / pit::PIT_FREQUENCY as u128) as u64
+}
+
+/// Convert nanoseconds to the number of ticks needed for them to pass
+pub fn nanos_to_ticks(nanos: u64) -> u64 {
+    let tick_nanos = ticks_to_nanos(1).max(1);
+    (nanos + tick_nanos - 1) / tick_nanos
+}
+
+/// Time since the timer was started
+pub fn uptime() -> Duration {
+    Duration::from_nanos(clock_nanos())
+}
+
+/// A point in time of the monotonic clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant {
+    nanos: u64,
+}
+
+impl Instant {
+    pub fn now() -> Instant {
+        Instant {
+            nanos: clock_nanos(),
+        }
+    }
+
+    /// Nanoseconds since boot
+    pub fn as_nanos(&self) -> u64 {
+        self.nanos
+    }
+
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.nanos.saturating_sub(earlier.nanos))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Instant {
+        Instant {
+            nanos: self.nanos + rhs.as_nanos() as u64,
+        }
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Duration {
+        self.duration_since(rhs)
+    }
+}
+
+/// Wait until `duration` has passed.
+///
+/// The CPU halts until the next interrupt while waiting, unless interrupts are
+/// disabled, in which case the clock cannot advance and this would hang.
+pub fn sleep(duration: Duration) {
+    use x86_64::instructions::interrupts;
+    assert!(interrupts::are_enabled(), "sleep with interrupts disabled");
+
+    let deadline = Instant::now() + duration;
+    while Instant::now() < deadline {
+        x86_64::instructions::hlt();
+    }
+}
+
+pub fn sleep_ms(ms: u64) {
+    sleep(Duration::from_millis(ms));
+}
+
+#[test_case]
+fn sleep_advances_clock() {
+    let start = Instant::now();
+    sleep_ms(20);
+    assert!(start.elapsed() >= Duration::from_millis(20));
+}
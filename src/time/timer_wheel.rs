@@ -0,0 +1,269 @@
+/*
+ * Kernel timers backed by a hierarchical timer wheel.
+ *
+ * The wheel has LEVELS levels of 64 slots. A slot of level n covers 64^n ticks,
+ * so level 0 holds the timers expiring within the next 64 ticks, level 1 those
+ * within 4096 ticks and so on. Every tick the current level 0 slot is expired;
+ * whenever a lower level wraps around, the next slot of the level above is
+ * cascaded, i.e. its timers are re-inserted into the lower levels.
+ *
+ * The wheel allocates while it moves timers around, so it is never touched in
+ * the timer interrupt. The interrupt only compares the tick count with the
+ * earliest expiry and schedules the bottom half, which advances the wheel and
+ * runs the callbacks in `run_expired`.
+ */
+use super::{nanos_to_ticks, ticks, Duration, Instant};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+const LEVELS: usize = 4;
+const SLOT_BITS: u32 = 6;
+const SLOTS: usize = 1 << SLOT_BITS;
+const SLOT_MASK: u64 = SLOTS as u64 - 1;
+
+pub type TimerCallback = Box<dyn FnMut() + Send>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TimerId(u64);
+
+struct Timer {
+    expires: u64,
+    period: Option<u64>,
+    // taken out while the callback runs
+    callback: Option<TimerCallback>,
+    // bumped by mod_timer so that stale slot entries are ignored
+    generation: u64,
+}
+
+struct TimerWheel {
+    slots: [[Vec<(TimerId, u64)>; SLOTS]; LEVELS],
+    timers: BTreeMap<TimerId, Timer>,
+    expired: Vec<(TimerId, u64)>,
+    current: u64,
+    next_id: u64,
+}
+
+// no timer expires before this tick, only changed with WHEEL held
+static NEXT_EXPIRY: AtomicU64 = AtomicU64::new(u64::MAX);
+
+const EMPTY_SLOT: Vec<(TimerId, u64)> = Vec::new();
+const EMPTY_LEVEL: [Vec<(TimerId, u64)>; SLOTS] = [EMPTY_SLOT; SLOTS];
+
+lazy_static! {
+    static ref WHEEL: Mutex<TimerWheel> = Mutex::new(TimerWheel {
+        slots: [EMPTY_LEVEL; LEVELS],
+        timers: BTreeMap::new(),
+        expired: Vec::new(),
+        current: ticks(),
+        next_id: 0,
+    });
+}
+
+impl TimerWheel {
+    /// Put a timer into the slot matching its expiry
+    fn insert(&mut self, id: TimerId, generation: u64, expires: u64) {
+        NEXT_EXPIRY.fetch_min(expires, Ordering::Relaxed);
+        if expires <= self.current {
+            self.expired.push((id, generation));
+            return;
+        }
+        let delta = expires - self.current;
+        let level = (0..LEVELS)
+            .find(|&level| delta < 1 << (SLOT_BITS * (level as u32 + 1)))
+            .unwrap_or(LEVELS - 1);
+        // timers too far in the future wait in the last slot reached before
+        let expires = expires.min(self.current + (1 << (SLOT_BITS * LEVELS as u32)) - 1);
+        let slot = ((expires >> (SLOT_BITS * level as u32)) & SLOT_MASK) as usize;
+        self.slots[level][slot].push((id, generation));
+    }
+
+    /// Re-insert all timers of a slot, they move to lower levels
+    fn cascade(&mut self, level: usize) {
+        let slot = ((self.current >> (SLOT_BITS * level as u32)) & SLOT_MASK) as usize;
+        let entries = core::mem::replace(&mut self.slots[level][slot], Vec::new());
+        for (id, generation) in entries {
+            if let Some(timer) = self.timers.get(&id) {
+                if timer.generation == generation {
+                    let expires = timer.expires;
+                    self.insert(id, generation, expires);
+                }
+            }
+        }
+    }
+
+    /// Advance the wheel to tick `now`, collecting the expired timers
+    fn advance(&mut self, now: u64) {
+        // the slots only hold stale entries then, nothing to walk through
+        if self.timers.is_empty() {
+            self.current = self.current.max(now);
+        }
+        while self.current < now {
+            self.current += 1;
+            // find the levels which wrapped around, cascade from the top down
+            let mut wrapped = 0;
+            while wrapped + 1 < LEVELS
+                && self.current & ((1 << (SLOT_BITS * (wrapped as u32 + 1))) - 1) == 0
+            {
+                wrapped += 1;
+            }
+            for level in (1..=wrapped).rev() {
+                self.cascade(level);
+            }
+
+            let slot = (self.current & SLOT_MASK) as usize;
+            let entries = core::mem::replace(&mut self.slots[0][slot], Vec::new());
+            for (id, generation) in entries {
+                if let Some(timer) = self.timers.get(&id) {
+                    if timer.generation == generation {
+                        let expires = timer.expires;
+                        self.insert(id, generation, expires);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Earliest expiry of the timers waiting in the wheel
+    fn next_expiry(&self) -> u64 {
+        self.timers
+            .values()
+            .filter(|timer| timer.callback.is_some())
+            .map(|timer| timer.expires)
+            .min()
+            .unwrap_or(u64::MAX)
+    }
+}
+
+fn deadline_to_tick(deadline: Instant) -> u64 {
+    nanos_to_ticks(deadline.as_nanos())
+}
+
+fn add(deadline: Instant, period: Option<Duration>, callback: TimerCallback) -> TimerId {
+    interrupts::without_interrupts(|| {
+        let mut wheel = WHEEL.lock();
+        let id = TimerId(wheel.next_id);
+        wheel.next_id += 1;
+        let expires = deadline_to_tick(deadline);
+        wheel.timers.insert(
+            id,
+            Timer {
+                expires,
+                period: period.map(|p| nanos_to_ticks(p.as_nanos() as u64).max(1)),
+                callback: Some(callback),
+                generation: 0,
+            },
+        );
+        wheel.insert(id, 0, expires);
+        id
+    })
+}
+
+/// Call `callback` once at `deadline`
+pub fn add_timer(deadline: Instant, callback: impl FnMut() + Send + 'static) -> TimerId {
+    add(deadline, None, Box::new(callback))
+}
+
+/// Call `callback` every `period`, starting one period from now
+#[allow(dead_code)]
+pub fn add_periodic_timer(period: Duration, callback: impl FnMut() + Send + 'static) -> TimerId {
+    add(Instant::now() + period, Some(period), Box::new(callback))
+}
+
+/// Move a pending timer to a new deadline. Returns false if it no longer exists.
+#[allow(dead_code)]
+pub fn mod_timer(id: TimerId, deadline: Instant) -> bool {
+    interrupts::without_interrupts(|| {
+        let mut wheel = WHEEL.lock();
+        let expires = deadline_to_tick(deadline);
+        let generation = match wheel.timers.get_mut(&id) {
+            Some(timer) => {
+                timer.generation += 1;
+                timer.expires = expires;
+                timer.generation
+            }
+            None => return false,
+        };
+        wheel.insert(id, generation, expires);
+        true
+    })
+}
+
+/// Cancel a timer. Returns false if it already expired or was deleted.
+pub fn del_timer(id: TimerId) -> bool {
+    interrupts::without_interrupts(|| WHEEL.lock().timers.remove(&id).is_some())
+}
+
+/// Whether timers are due and `run_expired` has to run. Called from the
+/// timer interrupt, takes no lock.
+pub fn has_expired() -> bool {
+    ticks() >= NEXT_EXPIRY.load(Ordering::Relaxed)
+}
+
+/// Advance the wheel and run the callbacks of all expired timers. Must not
+/// be called with the interrupt controller waiting for an EOI.
+pub fn run_expired() {
+    loop {
+        let next = interrupts::without_interrupts(|| {
+            let mut wheel = WHEEL.lock();
+            wheel.advance(ticks());
+            while let Some((id, generation)) = wheel.expired.pop() {
+                if let Some(timer) = wheel.timers.get_mut(&id) {
+                    if timer.generation == generation {
+                        if let Some(callback) = timer.callback.take() {
+                            return Some((id, generation, callback));
+                        }
+                    }
+                }
+            }
+            let next_expiry = wheel.next_expiry();
+            NEXT_EXPIRY.store(next_expiry, Ordering::Relaxed);
+            None
+        });
+        let (id, generation, mut callback) = match next {
+            Some(next) => next,
+            None => return,
+        };
+
+        callback();
+
+        interrupts::without_interrupts(|| {
+            let mut wheel = WHEEL.lock();
+            let mut reschedule = None;
+            let mut remove = false;
+            if let Some(timer) = wheel.timers.get_mut(&id) {
+                timer.callback = Some(callback);
+                // a timer modified by its own callback is already queued again
+                if timer.generation == generation {
+                    match timer.period {
+                        Some(period) => {
+                            timer.expires += period;
+                            reschedule = Some(timer.expires);
+                        }
+                        None => remove = true,
+                    }
+                }
+            }
+            if let Some(expires) = reschedule {
+                wheel.insert(id, generation, expires);
+            }
+            if remove {
+                wheel.timers.remove(&id);
+            }
+        });
+    }
+}
+
+#[test_case]
+fn timer_fires_after_deadline() {
+    use core::sync::atomic::{AtomicBool, Ordering};
+    static FIRED: AtomicBool = AtomicBool::new(false);
+    let deadline = Instant::now() + Duration::from_millis(5);
+    add_timer(deadline, || FIRED.store(true, Ordering::SeqCst));
+    super::sleep_ms(20);
+    assert!(FIRED.load(Ordering::SeqCst));
+}
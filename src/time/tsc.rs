@@ -0,0 +1,84 @@
+/*
+ * TSC (Time Stamp Counter)
+ *
+ * The TSC counts at a fixed rate on CPUs with an invariant TSC (CPUID
+ * 0x8000_0007, EDX bit 8). Its rate is not reported reliably, so it is
+ * measured at boot against the HPET if there is one, or else against PIT
+ * channel 2.
+ */
+use crate::drivers::{hpet, pit};
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+const CALIBRATION_MS: u64 = 50;
+
+static TSC_KHZ: AtomicU64 = AtomicU64::new(0);
+static INVARIANT: AtomicBool = AtomicBool::new(false);
+
+pub fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Whether the TSC rate is constant across P-, C- and T-states
+pub fn has_invariant_tsc() -> bool {
+    unsafe {
+        let max_extended_leaf = __cpuid(0x8000_0000).eax;
+        max_extended_leaf >= 0x8000_0007 && __cpuid(0x8000_0007).edx & (1 << 8) != 0
+    }
+}
+
+/// Measure the TSC frequency, returns it in kHz
+pub fn calibrate() -> u64 {
+    let khz = x86_64::instructions::interrupts::without_interrupts(|| {
+        if hpet::is_present() {
+            calibrate_with_hpet()
+        } else {
+            calibrate_with_pit()
+        }
+    });
+    INVARIANT.store(has_invariant_tsc(), Ordering::Relaxed);
+    TSC_KHZ.store(khz, Ordering::Relaxed);
+    serial_println!(
+        "tsc: {}.{:03} MHz, {}invariant, calibrated against the {}",
+        khz / 1000,
+        khz % 1000,
+        if is_invariant() { "" } else { "not " },
+        if hpet::is_present() { "HPET" } else { "PIT" }
+    );
+    khz
+}
+
+fn calibrate_with_hpet() -> u64 {
+    let hpet_start = hpet::nanos();
+    let tsc_start = rdtsc();
+    while hpet::nanos() - hpet_start < CALIBRATION_MS * 1_000_000 {
+        core::sync::atomic::spin_loop_hint();
+    }
+    let cycles = rdtsc() - tsc_start;
+    let nanos = hpet::nanos() - hpet_start;
+    cycles * 1_000_000 / nanos
+}
+
+fn calibrate_with_pit() -> u64 {
+    let mut stamps = [0u64; 2];
+    let mut index = 0;
+    pit::one_shot_wait(CALIBRATION_MS, || {
+        stamps[index] = rdtsc();
+        index += 1;
+    });
+    (stamps[1] - stamps[0]) / CALIBRATION_MS
+}
+
+/// The calibrated TSC frequency in kHz, 0 before calibration
+pub fn khz() -> u64 {
+    TSC_KHZ.load(Ordering::Relaxed)
+}
+
+pub fn is_invariant() -> bool {
+    INVARIANT.load(Ordering::Relaxed)
+}
+
+/// Convert a number of TSC cycles to nanoseconds
+pub fn cycles_to_nanos(cycles: u64) -> u64 {
+    (cycles as u128 * 1_000_000 / khz().max(1) as u128) as u64
+}
@@ -0,0 +1,137 @@
+/*
+ * Wall-clock time.
+ *
+ * The RTC is read once at boot. Afterwards the wall clock is the boot time plus
+ * the monotonic uptime, so it never jumps backwards and costs no port I/O.
+ */
+use super::uptime;
+use crate::drivers::rtc::{self, RtcTime};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// UNIX time in seconds at which the monotonic clock was zero
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+
+/// Broken-down UTC date and time
+pub type DateTime = RtcTime;
+
+/// A wall-clock reading
+#[derive(Debug, Clone, Copy)]
+pub struct WallClock {
+    /// Seconds since 1970-01-01 00:00:00 UTC
+    pub unix: u64,
+    pub nanos: u32,
+    pub date: DateTime,
+}
+
+/// Days since 1970-01-01 of a date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Inverse of `days_from_civil`
+fn civil_from_days(days: i64) -> (i64, u8, u8) {
+    let days = days + 719468;
+    let era = (if days >= 0 { days } else { days - 146096 }) / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u8;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u8;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Seconds since the epoch, None for dates before 1970 or fields out of range
+pub fn to_unix(date: &DateTime) -> Option<u64> {
+    let valid = date.year >= 1970
+        && (1..=12).contains(&date.month)
+        && date.day >= 1
+        && date.day <= days_in_month(date.year, date.month)
+        && date.hour < 24
+        && date.minute < 60
+        && date.second < 60;
+    if !valid {
+        return None;
+    }
+    let days = days_from_civil(date.year as i64, date.month as i64, date.day as i64);
+    Some(days as u64 * 86400 + date.hour as u64 * 3600 + date.minute as u64 * 60 + date.second as u64)
+}
+
+pub fn from_unix(unix: u64) -> DateTime {
+    let (year, month, day) = civil_from_days((unix / 86400) as i64);
+    let seconds = unix % 86400;
+    DateTime {
+        year: year as u16,
+        month,
+        day,
+        hour: (seconds / 3600) as u8,
+        minute: (seconds / 60 % 60) as u8,
+        second: (seconds % 60) as u8,
+    }
+}
+
+/// Read the RTC and anchor the wall clock to the monotonic clock
+pub fn init() {
+    let date = rtc::read_time();
+    let unix = to_unix(&date).unwrap_or_else(|| {
+        serial_println!("rtc: invalid date, the wall clock starts at 1970");
+        0
+    });
+    BOOT_TIME.store(unix.saturating_sub(uptime().as_secs()), Ordering::Relaxed);
+    serial_println!(
+        "rtc: {:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        date.year,
+        date.month,
+        date.day,
+        date.hour,
+        date.minute,
+        date.second
+    );
+}
+
+/// Current wall-clock time
+pub fn wall_clock_now() -> WallClock {
+    let uptime = uptime();
+    let unix = BOOT_TIME.load(Ordering::Relaxed) + uptime.as_secs();
+    WallClock {
+        unix,
+        nanos: uptime.subsec_nanos(),
+        date: from_unix(unix),
+    }
+}
+
+#[test_case]
+fn unix_time_round_trip() {
+    let date = DateTime { year: 2021, month: 3, day: 1, hour: 12, minute: 34, second: 56 };
+    assert_eq!(to_unix(&date), Some(1614602096));
+    assert_eq!(from_unix(1614602096), date);
+    assert_eq!(to_unix(&from_unix(0)), Some(0));
+}
+
+#[test_case]
+fn invalid_dates_are_rejected() {
+    let date = DateTime { year: 1969, month: 12, day: 31, hour: 23, minute: 59, second: 59 };
+    assert_eq!(to_unix(&date), None);
+    let date = DateTime { year: 2021, month: 2, day: 29, hour: 0, minute: 0, second: 0 };
+    assert_eq!(to_unix(&date), None);
+    let date = DateTime { year: 2020, month: 2, day: 29, hour: 0, minute: 0, second: 0 };
+    assert!(to_unix(&date).is_some());
+    // what a CMOS with garbage in its BCD registers may give
+    let date = DateTime { year: 2099, month: 0x1f, day: 45, hour: 25, minute: 61, second: 99 };
+    assert_eq!(to_unix(&date), None);
+}
@@ -0,0 +1,222 @@
+/*
+ * Lockup detectors.
+ *
+ * Soft lockup: a CPU keeps taking timer interrupts but never gets to run the
+ * scheduler, e.g. because a driver spins with preemption disabled or in a
+ * bottom half. The scheduler and the idle loop pet the watchdog, and the
+ * timer interrupt reports a lockup once the last pet is older than the
+ * threshold.
+ *
+ * Hard lockup: a CPU spins with interrupts disabled, so its timer interrupt
+ * never fires. With the `nmi_watchdog` feature the last CPU gives up idling
+ * and watches the timer tick count instead. If the ticks stop for the
+ * threshold, it sends an NMI to the timer CPU, which cannot be masked, and
+ * the NMI handler reports the lockup. The watcher needs the TSC or the HPET:
+ * the PIT clocksource advances with the very ticks that stopped.
+ *
+ * A report shows the interrupted stack frame, the backtrace and the running
+ * thread, then panics or, if configured, reboots the machine. A hook set with
+ * `set_lockup_hook` replaces both. Reports may interrupt a CPU holding the
+ * serial lock, so they break it first.
+ */
+use crate::smp::{self, ipi, percpu};
+use crate::task::scheduler;
+use crate::time::{self, clocksource, Duration, TIMER_HZ};
+use core::sync::atomic::{spin_loop_hint, AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use x86_64::structures::idt::InterruptStackFrame;
+
+// the external timer interrupt is only routed to the BSP
+const TIMER_CPU: usize = 0;
+
+// in timer ticks, zero while the watchdog is disarmed
+static THRESHOLD_TICKS: AtomicU64 = AtomicU64::new(0);
+static THRESHOLD_NANOS: AtomicU64 = AtomicU64::new(0);
+static REBOOT: AtomicBool = AtomicBool::new(false);
+// CPU running the hard lockup watcher, if any
+static WATCHER_CPU: AtomicUsize = AtomicUsize::new(usize::MAX);
+// a LockupHook as usize, 0 to panic or reboot
+static HOOK: AtomicUsize = AtomicUsize::new(0);
+
+/// Called with the kind of lockup and the CPU after the report was printed
+pub type LockupHook = fn(kind: &'static str, cpu: usize);
+
+crate::percpu! {
+    // timer tick of the last pet
+    static LAST_PET: AtomicU64 = AtomicU64::new(0);
+}
+crate::percpu! {
+    // set by the watcher right before it sends the NMI
+    static HARD_LOCKUP: AtomicBool = AtomicBool::new(false);
+}
+
+/// Arm the watchdog: report CPUs that did not schedule for `threshold`
+pub fn init(threshold: Duration) {
+    let ticks = time::ticks();
+    for cpu in 0..smp::cpu_count() {
+        LAST_PET.on(cpu).store(ticks, Ordering::Relaxed);
+    }
+    set_threshold(threshold);
+    #[cfg(feature = "nmi_watchdog")]
+    start_watcher();
+    serial_println!("watchdog: armed, threshold {}s", threshold.as_secs());
+}
+
+fn set_threshold(threshold: Duration) {
+    THRESHOLD_NANOS.store(threshold.as_nanos() as u64, Ordering::Relaxed);
+    THRESHOLD_TICKS.store(time::nanos_to_ticks(threshold.as_nanos() as u64), Ordering::Release);
+}
+
+/// Reboot instead of panicking on a lockup
+#[allow(dead_code)]
+pub fn set_reboot_on_lockup(reboot: bool) {
+    REBOOT.store(reboot, Ordering::Relaxed);
+}
+
+/// Run `hook` instead of panicking or rebooting after a lockup report, None
+/// restores the default. The hook runs in the interrupt handler that found
+/// the lockup, and the interrupted code goes on once it returns.
+#[allow(dead_code)]
+pub fn set_lockup_hook(hook: Option<LockupHook>) {
+    HOOK.store(hook.map_or(0, |hook| hook as usize), Ordering::Release);
+}
+
+/// Tell the watchdog this CPU is making progress
+pub fn pet() {
+    LAST_PET.get().store(time::ticks(), Ordering::Relaxed);
+}
+
+/// Timer ticks since this CPU last pet the watchdog
+fn stalled_ticks() -> u64 {
+    time::ticks().saturating_sub(LAST_PET.get().load(Ordering::Relaxed))
+}
+
+/// Called by the timer interrupt handler on every tick
+pub fn check_soft_lockup(stack_frame: &InterruptStackFrame) {
+    let threshold = THRESHOLD_TICKS.load(Ordering::Acquire);
+    if threshold == 0 {
+        return;
+    }
+    let stalled = stalled_ticks();
+    if stalled > threshold {
+        lockup("soft lockup", stalled * 1000 / TIMER_HZ as u64, stack_frame);
+    }
+}
+
+/// Called by the NMI handler, reports the lockup if the watcher sent the NMI.
+/// Returns whether it did.
+pub fn check_hard_lockup(stack_frame: &InterruptStackFrame) -> bool {
+    if !HARD_LOCKUP.get().swap(false, Ordering::AcqRel) {
+        return false;
+    }
+    lockup("hard lockup", THRESHOLD_NANOS.load(Ordering::Relaxed) / 1_000_000, stack_frame);
+    true
+}
+
+fn lockup(kind: &'static str, stalled_ms: u64, stack_frame: &InterruptStackFrame) {
+    let cpu = percpu::cpu_id();
+    // we may have interrupted the holder of the serial lock
+    crate::drivers::serial::break_lock();
+    serial_println!("==================================================");
+    serial_println!("watchdog: {} on CPU {}, stuck for {}ms", kind, cpu, stalled_ms);
+    serial_println!("{:#?}", stack_frame);
+    // the interrupted thread may hold the scheduler lock, so do not wait for it
+    match scheduler::try_current_id() {
+        Some(id) => serial_println!("current thread: {} ({})", id, scheduler::try_current_name().unwrap_or("?")),
+        None => serial_println!("current thread: none"),
+    }
+    // the frames of the handler lead into the interrupted code
+    crate::backtrace::print_current();
+    match HOOK.load(Ordering::Acquire) {
+        0 => {}
+        hook => {
+            let hook: LockupHook = unsafe { core::mem::transmute(hook) };
+            hook(kind, cpu);
+            // the next report only after another full threshold
+            pet();
+            return;
+        }
+    }
+    if REBOOT.load(Ordering::Relaxed) {
+        serial_println!("watchdog: rebooting");
+        crate::reboot();
+    }
+    panic!("watchdog: {} on CPU {}", kind, cpu);
+}
+
+/// Dedicate the last CPU to watching the timer of the BSP
+#[cfg(feature = "nmi_watchdog")]
+fn start_watcher() {
+    let cpus = smp::cpu_count();
+    if cpus < 2 {
+        serial_println!("watchdog: no CPU left for the NMI watchdog");
+        return;
+    }
+    if clocksource::current() == clocksource::Clocksource::Pit {
+        // the PIT clock stops together with the ticks it should watch
+        serial_println!("watchdog: the NMI watchdog needs the TSC or HPET clocksource");
+        return;
+    }
+    WATCHER_CPU.store(cpus - 1, Ordering::Release);
+    // wake it from hlt so its idle loop notices
+    ipi::send(cpus - 1, ipi::RESCHEDULE_VECTOR);
+}
+
+/// Whether the idle loop of `cpu` should run the hard lockup watcher
+pub fn is_watcher(cpu: usize) -> bool {
+    WATCHER_CPU.load(Ordering::Acquire) == cpu
+}
+
+/// Watch the timer ticks and NMI the timer CPU once they stop. Interrupts stay
+/// enabled, so IPIs are still served.
+pub fn hard_lockup_watcher() -> ! {
+    serial_println!("watchdog: CPU {} watches for hard lockups", percpu::cpu_id());
+    let mut last_ticks = time::ticks();
+    let mut last_progress = clocksource::now();
+    let mut fired = false;
+    loop {
+        spin_loop_hint();
+        let now = clocksource::now();
+        let ticks = time::ticks();
+        if ticks != last_ticks {
+            last_ticks = ticks;
+            last_progress = now;
+            fired = false;
+        } else if !fired && now - last_progress > THRESHOLD_NANOS.load(Ordering::Relaxed) {
+            fired = true;
+            HARD_LOCKUP.on(TIMER_CPU).store(true, Ordering::Release);
+            ipi::send_nmi(TIMER_CPU);
+        }
+    }
+}
+
+#[test_case]
+fn scheduling_pets_the_watchdog() {
+    crate::task::yield_now();
+    assert!(stalled_ticks() < TIMER_HZ as u64);
+}
+
+#[test_case]
+fn soft_lockup_is_detected() {
+    static FIRED: AtomicBool = AtomicBool::new(false);
+    fn report(_kind: &'static str, _cpu: usize) {
+        FIRED.store(true, Ordering::SeqCst);
+    }
+
+    let ticks = THRESHOLD_TICKS.load(Ordering::Acquire);
+    let nanos = THRESHOLD_NANOS.load(Ordering::Relaxed);
+    set_lockup_hook(Some(report));
+    set_threshold(Duration::from_millis(20));
+    pet();
+    // without preemption nothing schedules, so nothing pets the watchdog
+    scheduler::preempt_disable();
+    let start = time::ticks();
+    while !FIRED.load(Ordering::SeqCst) && time::ticks() - start < TIMER_HZ as u64 {
+        spin_loop_hint();
+    }
+    scheduler::preempt_enable();
+    set_lockup_hook(None);
+    THRESHOLD_NANOS.store(nanos, Ordering::Relaxed);
+    THRESHOLD_TICKS.store(ticks, Ordering::Release);
+    pet();
+    assert!(FIRED.load(Ordering::SeqCst));
+}